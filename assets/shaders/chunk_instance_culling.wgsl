@@ -0,0 +1,99 @@
+#import bevy_efficient_forest_rendering::occlusion as occlusion
+#import bevy_efficient_forest_rendering::chunk_instancing_functions as chunk_instancing
+
+// Tests every instance of a chunk batch against the view frustum and the depth pyramid and packs the visible ones into visible_instances.
+// The whole batch is culled in one dispatch, every instance carries the slot index of its chunk which picks its CullingChunk.
+// Each chunk has its own range of instances and its own draw in indirect_args, which the draw then reads the instance count from.
+// The counts are reset every frame on the cpu.
+
+struct CullingView {
+    planes: array<vec4<f32>, 5>, // left, right, bottom, top, near. The far plane is left out just like bevy's own culling
+    counts: vec4<u32>, // [nr instances in the batch, floats per instance, padding...]
+};
+
+struct CullingChunk {
+    chunk_transform: mat4x4<f32>,
+    bounding_sphere: vec4<f32>, // [x,y,z, radius] of the mesh, after the model_transform
+    chunk_scale: vec4<f32>, // [largest scale axis of the chunk transform, padding...]
+    counts: vec4<u32>, // [nr instances, padding, first instance in the batch, index of the instance count in indirect_args]. No instances for hidden chunks
+    chunk_min: vec4<f32>, // world space bounds of the whole chunk
+    chunk_max: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> culling: CullingView;
+@group(0) @binding(1)
+var<storage, read> instances: array<f32>;
+@group(0) @binding(2)
+var<storage, read_write> visible_instances: array<f32>;
+@group(0) @binding(3)
+var<storage, read_write> indirect_args: array<atomic<u32>>; // one set of draw args per chunk in the batch
+@group(0) @binding(4)
+var<storage, read> chunks: array<CullingChunk>;
+
+fn read_vec4(index: u32) -> vec4<f32> {
+    return vec4<f32>(instances[index], instances[index + 1u], instances[index + 2u], instances[index + 3u]);
+}
+
+@compute @workgroup_size(64, 1, 1)
+fn cull(
+    @builtin(global_invocation_id) invocation_id: vec3<u32>,
+    @builtin(num_workgroups) num_workgroups: vec3<u32>,
+) {
+    // Big batches spill over into y
+    let batch_index = invocation_id.x + invocation_id.y*num_workgroups.x*64u;
+    let stride = culling.counts.y;
+    if (batch_index >= culling.counts.x) {
+        return;
+    }
+    let first = batch_index*stride;
+    let chunk_index = u32(instances[first + stride - 1u]);
+    if (chunk_index >= arrayLength(&chunks)) {
+        return;
+    }
+    let chunk = chunks[chunk_index];
+    // Hidden chunks and what is left in a slot after its chunk shrank
+    let first_instance = chunk.counts.z;
+    if (batch_index < first_instance || batch_index - first_instance >= chunk.counts.x) {
+        return;
+    }
+
+    // Same answer for every instance, but saves testing them one by one
+    if (occlusion::is_occluded(chunk.chunk_min.xyz, chunk.chunk_max.xyz)) {
+        return;
+    }
+
+    // Place the bounding sphere the same way the vertex shader places the mesh
+    var instance: chunk_instancing::InstanceInput;
+    instance.xyz = read_vec4(first);
+#ifdef FULL_INSTANCE_TRANSFORM
+    instance.rotation = read_vec4(first + 4u);
+    instance.scale = read_vec4(first + 8u);
+    let scale = instance.scale.xyz*instance.xyz.w;
+    let center = chunk_instancing::quat_rotate(instance.rotation, chunk.bounding_sphere.xyz*scale) + instance.xyz.xyz;
+    let radius = chunk.bounding_sphere.w*max(scale.x, max(scale.y, scale.z));
+#else
+    let scale = instance.xyz.w*chunk_instancing::random_scale(instance);
+    let center = chunk_instancing::random_rotation(instance)*chunk.bounding_sphere.xyz*scale + instance.xyz.xyz;
+    let radius = chunk.bounding_sphere.w*scale;
+#endif
+
+    let world_center = (chunk.chunk_transform*vec4<f32>(center, 1.0)).xyz;
+    let world_radius = radius*chunk.chunk_scale.x;
+    for (var i = 0; i < 5; i = i + 1) {
+        let plane = culling.planes[i];
+        if (dot(plane.xyz, world_center) + plane.w + world_radius <= 0.0) {
+            return;
+        }
+    }
+
+    if (occlusion::is_occluded(world_center - world_radius, world_center + world_radius)) {
+        return;
+    }
+
+    let visible_index = atomicAdd(&indirect_args[chunk.counts.w], 1u);
+    let visible_first = (first_instance + visible_index)*stride;
+    for (var i = 0u; i < stride; i = i + 1u) {
+        visible_instances[visible_first + i] = instances[first + i];
+    }
+}
@@ -1,86 +1,128 @@
 
 #import bevy_pbr::mesh_types Mesh
-#import bevy_pbr::mesh_view_bindings view
+#import bevy_pbr::mesh_view_bindings view, fog, screen_space_ambient_occlusion_texture, globals
+#import bevy_pbr::mesh_view_types FOG_MODE_OFF
 #import bevy_pbr::mesh_bindings mesh
 
 // NOTE: Bindings must come before functions that use them!
 #import bevy_pbr::mesh_functions as mesh_functions
 #import bevy_pbr::pbr_functions as pbr_functions
+#import bevy_pbr::pbr_bindings as pbr_bindings
+#import bevy_pbr::pbr_types as pbr_types
+#import bevy_core_pipeline::tonemapping screen_space_dither, powsafe, tone_mapping
+#import bevy_efficient_forest_rendering::chunk_instancing_functions as chunk_instancing
+
+#ifdef SCREEN_SPACE_AMBIENT_OCCLUSION
+#import bevy_pbr::gtao_utils gtao_multibounce
+#endif
 
 struct Vertex {
     @location(0) position: vec3<f32>,
     @location(1) normal: vec3<f32>,
+#ifdef VERTEX_UVS
     @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(3) tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(4) color: vec4<f32>,
+#endif
     @builtin(instance_index) instance_index: u32,
 
 };
 
-struct InstanceInput {
-    @location(3) xyz: vec4<f32>,
-}
-
-struct PlantChunk{
-    model_transform: mat4x4<f32>
-}
-
-@group(3) @binding(0)
-var<uniform> plant_chunk: PlantChunk;
-
-
+// Same locations as bevy_pbr::mesh_vertex_output so the StandardMaterial fragment works as usual
 struct VertexOutput {
     @builtin(position) clip_position: vec4<f32>,
     @location(0) world_position: vec4<f32>,
     @location(1) world_normal: vec3<f32>,
+#ifdef VERTEX_UVS
     @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(3) world_tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(4) color: vec4<f32>,
+#endif
+#ifdef INSTANCE_TINT
+    @location(5) tint: vec4<f32>,
+#endif
 };
 
 
 
-// [0,1.0]
-fn rand(co: vec2<f32>, seed: f32)-> f32{
-    return fract(sin(dot(co, vec2(12.9898, 78.233))) * 43758.5453);
-}
-
 @vertex
 fn vertex(vertex: Vertex,
-    instance: InstanceInput,
+    instance: chunk_instancing::InstanceInput,
 ) -> VertexOutput {
 
     var out: VertexOutput;
+#ifdef VERTEX_COLORS
+    out.color = vertex.color;
+#endif
+#ifdef INSTANCE_TINT
+    out.tint = instance.tint;
+#endif
+
+#ifdef CHUNK_BATCH
+    let chunk_mesh = chunk_instancing::chunk_meshes[u32(instance.chunk_index)];
+    let model = chunk_mesh.model;
+    let inverse_transpose_model = chunk_mesh.inverse_transpose_model;
+#else
+    let model = mesh.model;
+    let inverse_transpose_model = mesh.inverse_transpose_model;
+#endif
+
+#ifdef BILLBOARD
+    let facing = chunk_instancing::billboard_facing(view.view, inverse_transpose_model);
+    let position = chunk_instancing::billboard_position(vertex.position, instance, facing);
+    let normals = facing;
+#ifdef VERTEX_TANGENTS
+    let tangents = chunk_instancing::billboard_tangent(facing);
+#endif
+#ifdef VERTEX_UVS
+    out.uv = chunk_instancing::billboard_uv(vertex.uv, instance, facing);
+#endif
+#else
+#ifdef VERTEX_UVS
     out.uv = vertex.uv;
-
-    let rand_scale = rand(vec2<f32>(instance.xyz.y, 42.546*sin(instance.xyz.x)), 3.0)*0.2+0.9;
-    let transformed_position = plant_chunk.model_transform*vec4<f32>(vertex.position, 1.0)*instance.xyz.w*rand_scale;
-
-    let rot_z = rand(vec2<f32>(instance.xyz.x, 10.1512515*cos(instance.xyz.y)), 1.0)*3.1415*2.0;
-
-    let rot_mat = mat2x2<f32>(vec2<f32>(cos(rot_z), -sin(rot_z)), vec2<f32>(sin(rot_z), cos(rot_z)));
-    let rotated_xy = rot_mat*transformed_position.xy;
-    let position= vec4<f32>(rotated_xy.x+instance.xyz.x, rotated_xy.y+instance.xyz.y, transformed_position.z+instance.xyz.z, 1.0);
-
-    //Somthing not right about the normals?
-    let transformed_normals = plant_chunk.model_transform*vec4<f32>(vertex.normal, 1.0);
-    let rotated_normals = rot_mat*transformed_normals.xy;
-    let normals= vec3<f32>(rotated_normals.x,rotated_normals.y,transformed_normals.z);
-
-    out.world_position = mesh_functions::mesh_position_local_to_world(mesh.model, position);
-    out.world_normal = mesh_functions::mesh_normal_local_to_world(normals);
+#endif
+#ifdef SWAY_VERTEX_COLORS
+    let sway_weights = vertex.color.rg;
+#else
+    let sway_weights = chunk_instancing::height_sway_weights(vertex.position, instance);
+#endif
+    let position = chunk_instancing::wind_sway(
+        chunk_instancing::instance_position(vertex.position, instance),
+        vertex.position,
+        sway_weights,
+        instance,
+        model,
+        inverse_transpose_model,
+        globals.time,
+    );
+    let normals = chunk_instancing::instance_normal(vertex.normal, instance);
+#ifdef VERTEX_TANGENTS
+    let tangents = chunk_instancing::instance_tangent(vertex.tangent.xyz, instance);
+#endif
+#endif
+
+    out.world_position = mesh_functions::mesh_position_local_to_world(model, position);
+    // Same as mesh_functions::mesh_normal_local_to_world but that one always reads the mesh uniform
+    out.world_normal = normalize(mat3x3<f32>(
+        inverse_transpose_model[0].xyz,
+        inverse_transpose_model[1].xyz,
+        inverse_transpose_model[2].xyz,
+    )*normals);
+#ifdef VERTEX_TANGENTS
+    out.world_tangent = mesh_functions::mesh_tangent_local_to_world(model, vec4<f32>(tangents, vertex.tangent.w));
+#endif
     out.clip_position = mesh_functions::mesh_position_world_to_clip(out.world_position);
     return out;
 }
 
-@group(4) @binding(0)
-var diffuse_texture: texture_2d<f32>;
-@group(4) @binding(1)
-var diffuse_sampler: sampler;
-
-// @fragment
-// fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
-//     return textureSample(diffuse_texture, diffuse_sampler, in.uv);
-//     // return  vec4<f32>(0.5,0.5,0.5,1.0);
-// }
-
-
 
 
 struct FragmentInput {
@@ -88,35 +130,149 @@ struct FragmentInput {
     @builtin(position) frag_coord: vec4<f32>,
     @location(0) world_position: vec4<f32>,
     @location(1) world_normal: vec3<f32>,
+#ifdef VERTEX_UVS
     @location(2) uv: vec2<f32>,
+#endif
+#ifdef VERTEX_TANGENTS
+    @location(3) world_tangent: vec4<f32>,
+#endif
+#ifdef VERTEX_COLORS
+    @location(4) color: vec4<f32>,
+#endif
+#ifdef INSTANCE_TINT
+    @location(5) tint: vec4<f32>,
+#endif
 };
 
+// Same as the StandardMaterial fragment in bevy_pbr (pbr.wgsl) plus the instance tint and alpha mask override
 @fragment
 fn fragment(in: FragmentInput) -> @location(0) vec4<f32> {
-    // Prepare a 'processed' StandardMaterial by sampling all textures to resolve
-    // the material members
-    var pbr_input: pbr_functions::PbrInput = pbr_functions::pbr_input_new();
-
-    pbr_input.material.base_color = textureSample(diffuse_texture, diffuse_sampler, in.uv);
-    pbr_input.material.reflectance = 0.0;
-    // pbr_input.material.emissive = 0.0;
-
-    pbr_input.frag_coord = in.frag_coord;
-    pbr_input.world_position = in.world_position;
-    pbr_input.world_normal = in.world_normal;
-
-    pbr_input.is_orthographic = view.projection[3].w == 1.0;
-
-    pbr_input.N = pbr_functions::apply_normal_mapping(
-        pbr_input.material.flags,
-        pbr_input.world_normal,
-        in.uv,
-        view.mip_bias,
-    );
-    pbr_input.V = pbr_functions::calculate_view(in.world_position, pbr_input.is_orthographic);
-
-    return pbr_functions::pbr(pbr_input);
-
-    // Retrun dummy color
-    // return vec4<f32>(0.5,0.5,0.5,1.0);
+    // Copy that gets the alpha mode overrides, the texture branches still use the bound material so control flow stays uniform
+    var material: pbr_types::StandardMaterial = pbr_bindings::material;
+#ifdef CHUNK_ALPHA_MASK
+    material.flags = (material.flags & ~pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_RESERVED_BITS) | pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_MASK;
+    material.alpha_cutoff = chunk_instancing::plant_chunk.alpha_cutoff;
+#endif
+
+    var output_color: vec4<f32> = pbr_bindings::material.base_color;
+
+    let is_orthographic = view.projection[3].w == 1.0;
+
+#ifdef VERTEX_COLORS
+    output_color = output_color * in.color;
+#endif
+#ifdef INSTANCE_TINT
+    output_color = output_color * in.tint;
+#endif
+#ifdef VERTEX_UVS
+    if ((pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT) != 0u) {
+        output_color = output_color * textureSampleBias(pbr_bindings::base_color_texture, pbr_bindings::base_color_sampler, in.uv, view.mip_bias);
+    }
+#endif
+
+#ifdef ALPHA_TO_COVERAGE
+    // Sharpen alpha around the cutoff, the coverage mask then gives antialiased edges instead of discarding
+    output_color.a = clamp((output_color.a - material.alpha_cutoff) / max(fwidth(output_color.a), 0.0001) + 0.5, 0.0, 1.0);
+    material.flags = (material.flags & ~pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_RESERVED_BITS) | pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_BLEND;
+#endif
+
+    // NOTE: Unlit bit not set means == 0 is true, so the true case is if lit
+    if ((pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_UNLIT_BIT) == 0u) {
+        // Prepare a 'processed' StandardMaterial by sampling all textures to resolve
+        // the material members
+        var pbr_input: pbr_functions::PbrInput = pbr_functions::pbr_input_new();
+
+        pbr_input.material.base_color = output_color;
+        pbr_input.material.reflectance = pbr_bindings::material.reflectance;
+        pbr_input.material.flags = material.flags;
+        pbr_input.material.alpha_cutoff = material.alpha_cutoff;
+
+        var emissive: vec4<f32> = pbr_bindings::material.emissive;
+#ifdef VERTEX_UVS
+        if ((pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_EMISSIVE_TEXTURE_BIT) != 0u) {
+            emissive = vec4<f32>(emissive.rgb * textureSampleBias(pbr_bindings::emissive_texture, pbr_bindings::emissive_sampler, in.uv, view.mip_bias).rgb, 1.0);
+        }
+#endif
+        pbr_input.material.emissive = emissive;
+
+        var metallic: f32 = pbr_bindings::material.metallic;
+        var perceptual_roughness: f32 = pbr_bindings::material.perceptual_roughness;
+#ifdef VERTEX_UVS
+        if ((pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_METALLIC_ROUGHNESS_TEXTURE_BIT) != 0u) {
+            let metallic_roughness = textureSampleBias(pbr_bindings::metallic_roughness_texture, pbr_bindings::metallic_roughness_sampler, in.uv, view.mip_bias);
+            // Sampling from GLTF standard channels for now
+            metallic = metallic * metallic_roughness.b;
+            perceptual_roughness = perceptual_roughness * metallic_roughness.g;
+        }
+#endif
+        pbr_input.material.metallic = metallic;
+        pbr_input.material.perceptual_roughness = perceptual_roughness;
+
+        var occlusion: vec3<f32> = vec3(1.0);
+#ifdef VERTEX_UVS
+        if ((pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_OCCLUSION_TEXTURE_BIT) != 0u) {
+            occlusion = vec3(textureSampleBias(pbr_bindings::occlusion_texture, pbr_bindings::occlusion_sampler, in.uv, view.mip_bias).r);
+        }
+#endif
+#ifdef SCREEN_SPACE_AMBIENT_OCCLUSION
+        let ssao = textureLoad(screen_space_ambient_occlusion_texture, vec2<i32>(in.frag_coord.xy), 0i).r;
+        let ssao_multibounce = gtao_multibounce(ssao, pbr_input.material.base_color.rgb);
+        occlusion = min(occlusion, ssao_multibounce);
+#endif
+        pbr_input.occlusion = occlusion;
+
+        pbr_input.frag_coord = in.frag_coord;
+        pbr_input.world_position = in.world_position;
+
+        pbr_input.world_normal = pbr_functions::prepare_world_normal(
+            in.world_normal,
+            (pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_DOUBLE_SIDED_BIT) != 0u,
+            in.is_front,
+        );
+
+        pbr_input.is_orthographic = is_orthographic;
+
+        pbr_input.N = pbr_functions::apply_normal_mapping(
+            pbr_bindings::material.flags,
+            pbr_input.world_normal,
+#ifdef VERTEX_TANGENTS
+#ifdef STANDARDMATERIAL_NORMAL_MAP
+            in.world_tangent,
+#endif
+#endif
+#ifdef VERTEX_UVS
+            in.uv,
+#endif
+            view.mip_bias,
+        );
+        pbr_input.V = pbr_functions::calculate_view(in.world_position, is_orthographic);
+
+        pbr_input.flags = mesh.flags;
+
+        output_color = pbr_functions::pbr(pbr_input);
+    } else {
+        output_color = pbr_functions::alpha_discard(material, output_color);
+    }
+
+    // fog
+    if (fog.mode != FOG_MODE_OFF && (pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_FOG_ENABLED_BIT) != 0u) {
+        output_color = pbr_functions::apply_fog(fog, output_color, in.world_position.xyz, view.world_position.xyz);
+    }
+
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color, view.color_grading);
+#ifdef DEBAND_DITHER
+    var output_rgb = output_color.rgb;
+    output_rgb = powsafe(output_rgb, 1.0 / 2.2);
+    output_rgb = output_rgb + screen_space_dither(in.frag_coord.xy);
+    // This conversion back to linear space is required because our output texture format is
+    // SRGB; the GPU will assume our output is linear and will apply an SRGB conversion.
+    output_rgb = powsafe(output_rgb, 2.2);
+    output_color = vec4(output_rgb, output_color.a);
+#endif
+#endif
+#ifdef PREMULTIPLY_ALPHA
+    output_color = pbr_functions::premultiply_alpha(material.flags, output_color);
+#endif
+    return output_color;
 }
@@ -0,0 +1,214 @@
+#define_import_path bevy_efficient_forest_rendering::chunk_instancing_functions
+
+// Shared between the main pass and the shadow pass so the instances end up in the same place
+
+#import bevy_efficient_forest_rendering::wind Wind, wind_zone_push
+
+struct InstanceInput {
+    @location(8) xyz: vec4<f32>, // [x,y,z, scale]
+#ifdef FULL_INSTANCE_TRANSFORM
+    @location(9) rotation: vec4<f32>, // quaternion
+    @location(10) scale: vec4<f32>, // [x,y,z, unused]
+#endif
+#ifdef INSTANCE_TINT
+    @location(11) tint: vec4<f32>,
+#endif
+#ifdef CHUNK_BATCH
+    @location(12) chunk_index: f32, // index in chunk_meshes
+#endif
+}
+
+struct PlantChunk{
+    model_transform: mat4x4<f32>,
+    normal_transform: mat3x3<f32>, // inverse transpose of model_transform
+    alpha_cutoff: f32, // only used with CHUNK_ALPHA_MASK
+    impostor_columns: f32, // only used with BILLBOARD
+    stiffness: f32, // 0.0 sways freely in the wind, 1.0 doesn't move
+}
+
+@group(3) @binding(0)
+var<uniform> plant_chunk: PlantChunk;
+
+// Same wind as the grass
+@group(3) @binding(2)
+var<uniform> wind: Wind;
+
+#ifdef CHUNK_BATCH
+// All chunks of a batch are drawn at once, so the transforms come from here instead of the mesh uniform
+struct ChunkMesh {
+    model: mat4x4<f32>,
+    inverse_transpose_model: mat4x4<f32>,
+};
+
+@group(3) @binding(1)
+var<storage, read> chunk_meshes: array<ChunkMesh>;
+#endif
+
+
+// [0,1.0]
+fn rand(co: vec2<f32>, seed: f32)-> f32{
+    return fract(sin(dot(co, vec2(12.9898, 78.233))) * 43758.5453);
+}
+
+fn quat_rotate(q: vec4<f32>, v: vec3<f32>) -> vec3<f32> {
+    return v + 2.0 * cross(q.xyz, cross(q.xyz, v) + q.w * v);
+}
+
+#ifndef FULL_INSTANCE_TRANSFORM
+fn random_scale(instance: InstanceInput) -> f32 {
+    return rand(vec2<f32>(instance.xyz.y, 42.546*sin(instance.xyz.x)), 3.0)*0.2+0.9;
+}
+
+fn random_rotation(instance: InstanceInput) -> mat3x3<f32> {
+    let rot_z = rand(vec2<f32>(instance.xyz.x, 10.1512515*cos(instance.xyz.y)), 1.0)*3.1415*2.0;
+    return mat3x3<f32>(
+        vec3<f32>(cos(rot_z), -sin(rot_z), 0.0),
+        vec3<f32>(sin(rot_z), cos(rot_z), 0.0),
+        vec3<f32>(0.0, 0.0, 1.0),
+    );
+}
+#endif
+
+fn instance_scale(instance: InstanceInput) -> vec3<f32> {
+#ifdef FULL_INSTANCE_TRANSFORM
+    return instance.scale.xyz*instance.xyz.w;
+#else
+    return vec3<f32>(instance.xyz.w*random_scale(instance));
+#endif
+}
+
+// Vertex position of the mesh -> position in the chunk
+fn instance_position(vertex_position: vec3<f32>, instance: InstanceInput) -> vec4<f32> {
+    let model_position = (plant_chunk.model_transform*vec4<f32>(vertex_position, 1.0)).xyz;
+#ifdef FULL_INSTANCE_TRANSFORM
+    // Deliberately placed instances, no randomization
+    let instance_scale = instance.scale.xyz*instance.xyz.w;
+    return vec4<f32>(quat_rotate(instance.rotation, model_position*instance_scale)+instance.xyz.xyz, 1.0);
+#else
+    return vec4<f32>(random_rotation(instance)*model_position*instance.xyz.w*random_scale(instance)+instance.xyz.xyz, 1.0);
+#endif
+}
+
+// Normals go through the inverse transpose, tangents follow the positions
+fn instance_normal(vertex_normal: vec3<f32>, instance: InstanceInput) -> vec3<f32> {
+    let model_normal = plant_chunk.normal_transform*vertex_normal;
+#ifdef FULL_INSTANCE_TRANSFORM
+    let instance_scale = instance.scale.xyz*instance.xyz.w;
+    return quat_rotate(instance.rotation, normalize(model_normal/instance_scale));
+#else
+    //Uniform scale so only the rotation matters for normals
+    return random_rotation(instance)*normalize(model_normal);
+#endif
+}
+
+fn instance_tangent(vertex_tangent: vec3<f32>, instance: InstanceInput) -> vec3<f32> {
+    let model_tangent = (plant_chunk.model_transform*vec4<f32>(vertex_tangent, 0.0)).xyz;
+#ifdef FULL_INSTANCE_TRANSFORM
+    let instance_scale = instance.scale.xyz*instance.xyz.w;
+    return quat_rotate(instance.rotation, normalize(model_tangent*instance_scale));
+#else
+    return random_rotation(instance)*normalize(model_tangent);
+#endif
+}
+
+
+// Height above the model origin, in chunk units
+fn sway_height(vertex_position: vec3<f32>, instance: InstanceInput) -> f32 {
+    let model_position = (plant_chunk.model_transform*vec4<f32>(vertex_position, 1.0)).xyz;
+    return max(model_position.z, 0.0)*instance_scale(instance).z;
+}
+
+// [trunk bend, leaf flutter] when the mesh has no painted weights, stiff near the ground and bending freely higher up
+fn height_sway_weights(vertex_position: vec3<f32>, instance: InstanceInput) -> vec2<f32> {
+    let height = sway_height(vertex_position, instance);
+    return vec2<f32>(height/(height + 1.0));
+}
+
+// Bends the instance around its origin away from the wind and lets the leaves flutter on top of that.
+// position is the result of instance_position, every instance gets its own phase so they don't sway in sync
+fn wind_sway(position: vec4<f32>, vertex_position: vec3<f32>, weights: vec2<f32>, instance: InstanceInput, model: mat4x4<f32>, inverse_transpose_model: mat4x4<f32>, time: f32) -> vec4<f32> {
+    let flexibility = 1.0 - plant_chunk.stiffness;
+    if (flexibility <= 0.0) {
+        return position;
+    }
+    let strength = wind.direction_strength.z*flexibility;
+    let world_to_local = transpose(mat3x3<f32>(
+        inverse_transpose_model[0].xyz,
+        inverse_transpose_model[1].xyz,
+        inverse_transpose_model[2].xyz,
+    ));
+    let wind_dir = (world_to_local*vec3<f32>(wind.direction_strength.xy, 0.0)).xy;
+    let phase = rand(instance.xyz.xy, 0.0)*6.2831;
+    let height = sway_height(vertex_position, instance);
+
+    // Trunk bend, leans with the wind and swings around that, stronger during gusts
+    let gust = 1.0 + wind.direction_strength.w*0.5*(sin(time*0.5 + phase*0.3) + 1.0);
+    var bend = wind_dir*strength*gust*(0.15 + 0.1*sin(time*1.3 + phase));
+
+    // Wind zones lean the whole instance away, shaking a bit. Evaluated at the instance origin so it bends as one
+    let instance_origin = (model*vec4<f32>(instance.xyz.xyz, 1.0)).xyz;
+    var zone_push = vec2<f32>(0.0, 0.0);
+    for (var i = 0u; i < wind.zone_count.x; i = i + 1u) {
+        zone_push = zone_push + wind_zone_push(wind.zones[i], instance_origin);
+    }
+    let shake = 0.85 + 0.15*sin(time*9.0 + phase);
+    bend = bend + (world_to_local*vec3<f32>(zone_push, 0.0)).xy*0.5*flexibility*shake;
+
+    let bend_offset = bend*height*weights.x;
+    // Keeps the length of the trunk about the same when it leans
+    let drop = 0.5*dot(bend_offset, bend_offset)/max(height, 0.001);
+    var swayed = position.xyz + vec3<f32>(bend_offset, -drop);
+
+    // Leaf flutter
+    let flutter_phase = time*wind.gust_turbulence.z*3.0 + phase + dot(vertex_position, vec3<f32>(1.7, 2.3, 1.9));
+    swayed = swayed + vec3<f32>(sin(flutter_phase), cos(flutter_phase*1.3), sin(flutter_phase*0.7))*0.1*strength*weights.y;
+    return vec4<f32>(swayed, position.w);
+}
+
+#ifdef BILLBOARD
+// Impostors are quads in the xz plane (see get_impostor_mesh) turned towards the viewer around z.
+// The chunk's model_transform is meant for the full mesh and is not applied to the quad.
+
+// Horizontal direction towards the viewer in chunk space, view_transform is the camera (or light) transform
+fn billboard_facing(view_transform: mat4x4<f32>, mesh_inverse_transpose_model: mat4x4<f32>) -> vec3<f32> {
+    let world_to_local = transpose(mat3x3<f32>(
+        mesh_inverse_transpose_model[0].xyz,
+        mesh_inverse_transpose_model[1].xyz,
+        mesh_inverse_transpose_model[2].xyz,
+    ));
+    var facing = (world_to_local*view_transform[2].xyz).xy;
+    // Looking straight down, use the camera up vector instead
+    if (dot(facing, facing) < 0.000001) {
+        facing = -(world_to_local*view_transform[1].xyz).xy;
+    }
+    return vec3<f32>(normalize(facing), 0.0);
+}
+
+fn billboard_scale(instance: InstanceInput) -> vec2<f32> {
+    return instance_scale(instance).xz;
+}
+
+fn billboard_position(vertex_position: vec3<f32>, instance: InstanceInput, facing: vec3<f32>) -> vec4<f32> {
+    let right = cross(vec3<f32>(0.0, 0.0, 1.0), facing);
+    let scale = billboard_scale(instance);
+    return vec4<f32>(right*vertex_position.x*scale.x + vec3<f32>(0.0, 0.0, vertex_position.z*scale.y) + instance.xyz.xyz, 1.0);
+}
+
+fn billboard_tangent(facing: vec3<f32>) -> vec3<f32> {
+    return cross(vec3<f32>(0.0, 0.0, 1.0), facing);
+}
+
+// Picks the atlas column rendered from the closest angle.
+// Column 0 is the tree seen from -y, the following columns go counter clockwise around it
+fn billboard_uv(uv: vec2<f32>, instance: InstanceInput, facing: vec3<f32>) -> vec2<f32> {
+    let columns = max(plant_chunk.impostor_columns, 1.0);
+#ifdef FULL_INSTANCE_TRANSFORM
+    let local_facing = quat_rotate(vec4<f32>(-instance.rotation.xyz, instance.rotation.w), facing);
+#else
+    let local_facing = transpose(random_rotation(instance))*facing;
+#endif
+    let angle = atan2(local_facing.x, -local_facing.y);
+    let column = floor(fract(angle/(2.0*3.1415926) + 0.5/columns)*columns);
+    return vec2<f32>((column + uv.x)/columns, uv.y);
+}
+#endif
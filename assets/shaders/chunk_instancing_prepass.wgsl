@@ -0,0 +1,128 @@
+#import bevy_pbr::prepass_bindings
+#import bevy_pbr::mesh_view_bindings as mesh_view_bindings
+#import bevy_pbr::mesh_functions as mesh_functions
+#import bevy_pbr::mesh_bindings mesh
+#import bevy_pbr::pbr_bindings as pbr_bindings
+#import bevy_pbr::pbr_types as pbr_types
+#import bevy_efficient_forest_rendering::chunk_instancing_functions as chunk_instancing
+
+// Depth only version of chunk_instancing.wgsl, used when rendering the shadow maps
+
+struct Vertex {
+    @location(0) position: vec3<f32>,
+#ifdef VERTEX_UVS
+    @location(1) uv: vec2<f32>,
+#endif
+#ifdef SWAY_VERTEX_COLORS
+    @location(4) color: vec4<f32>,
+#endif
+};
+
+struct VertexOutput {
+    @builtin(position) clip_position: vec4<f32>,
+#ifdef VERTEX_UVS
+    @location(0) uv: vec2<f32>,
+#endif
+#ifdef DEPTH_CLAMP_ORTHO
+    @location(5) clip_position_unclamped: vec4<f32>,
+#endif
+};
+
+@vertex
+fn vertex(vertex: Vertex,
+    instance: chunk_instancing::InstanceInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+
+#ifdef BILLBOARD
+    // Same binding as prepass_bindings::view, mesh_functions already pulls it in through mesh_view_bindings
+    let facing = chunk_instancing::billboard_facing(mesh_view_bindings::view.view, mesh.inverse_transpose_model);
+    let position = chunk_instancing::billboard_position(vertex.position, instance, facing);
+#else
+#ifdef SWAY_VERTEX_COLORS
+    let sway_weights = vertex.color.rg;
+#else
+    let sway_weights = chunk_instancing::height_sway_weights(vertex.position, instance);
+#endif
+    let position = chunk_instancing::wind_sway(
+        chunk_instancing::instance_position(vertex.position, instance),
+        vertex.position,
+        sway_weights,
+        instance,
+        mesh.model,
+        mesh.inverse_transpose_model,
+        bevy_pbr::prepass_bindings::globals.time,
+    );
+#endif
+    out.clip_position = mesh_functions::mesh_position_local_to_clip(mesh.model, position);
+#ifdef DEPTH_CLAMP_ORTHO
+    out.clip_position_unclamped = out.clip_position;
+    out.clip_position.z = min(out.clip_position.z, 1.0);
+#endif
+
+#ifdef VERTEX_UVS
+#ifdef BILLBOARD
+    out.uv = chunk_instancing::billboard_uv(vertex.uv, instance, facing);
+#else
+    out.uv = vertex.uv;
+#endif
+#endif
+    return out;
+}
+
+struct FragmentInput {
+    @builtin(position) frag_coord: vec4<f32>,
+#ifdef VERTEX_UVS
+    @location(0) uv: vec2<f32>,
+#endif
+#ifdef DEPTH_CLAMP_ORTHO
+    @location(5) clip_position_unclamped: vec4<f32>,
+#endif
+};
+
+// Same cutoff as the main pass, otherwise leaves cast square shadows
+fn alpha_discard(in: FragmentInput) {
+#ifdef MAY_DISCARD
+    var output_color: vec4<f32> = pbr_bindings::material.base_color;
+#ifdef VERTEX_UVS
+    if ((pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_BASE_COLOR_TEXTURE_BIT) != 0u) {
+        output_color = output_color * textureSampleBias(pbr_bindings::base_color_texture, pbr_bindings::base_color_sampler, in.uv, bevy_pbr::prepass_bindings::view.mip_bias);
+    }
+#endif
+
+#ifdef CHUNK_ALPHA_MASK
+    if (output_color.a < chunk_instancing::plant_chunk.alpha_cutoff) {
+        discard;
+    }
+#else
+    let alpha_mode = pbr_bindings::material.flags & pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_RESERVED_BITS;
+    if (alpha_mode == pbr_types::STANDARD_MATERIAL_FLAGS_ALPHA_MODE_MASK && output_color.a < pbr_bindings::material.alpha_cutoff) {
+        discard;
+    }
+#endif
+#endif
+}
+
+#ifdef PREPASS_FRAGMENT
+struct FragmentOutput {
+#ifdef DEPTH_CLAMP_ORTHO
+    @builtin(frag_depth) frag_depth: f32,
+#endif
+}
+
+@fragment
+fn fragment(in: FragmentInput) -> FragmentOutput {
+    alpha_discard(in);
+
+    var out: FragmentOutput;
+#ifdef DEPTH_CLAMP_ORTHO
+    out.frag_depth = in.clip_position_unclamped.z;
+#endif
+    return out;
+}
+#else
+@fragment
+fn fragment(in: FragmentInput) {
+    alpha_discard(in);
+}
+#endif
@@ -0,0 +1,49 @@
+// Builds one level of the depth pyramid used for occlusion culling.
+// The first level reads the view depth texture, the following levels the level above.
+// Every texel keeps the farthest depth of the texels it covers, with reverse z that is the smallest value.
+
+#ifdef FIRST_LEVEL
+#ifdef MULTISAMPLED
+@group(0) @binding(0)
+var source: texture_depth_multisampled_2d;
+#else
+@group(0) @binding(0)
+var source: texture_depth_2d;
+#endif
+#else
+@group(0) @binding(0)
+var source: texture_2d<f32>;
+#endif
+
+@group(0) @binding(1)
+var destination: texture_storage_2d<r32float, write>;
+
+fn load_source(texel: vec2<i32>) -> f32 {
+#ifdef FIRST_LEVEL
+    // Sample 0 for multisampled depth, mip 0 otherwise
+    return textureLoad(source, texel, 0);
+#else
+    return textureLoad(source, texel, 0).x;
+#endif
+}
+
+@compute @workgroup_size(8, 8, 1)
+fn downsample(@builtin(global_invocation_id) invocation_id: vec3<u32>) {
+    let destination_size = vec2<u32>(textureDimensions(destination));
+    if (any(invocation_id.xy >= destination_size)) {
+        return;
+    }
+    let source_size = vec2<u32>(textureDimensions(source));
+
+    // Source texels covered by this texel, up to 3x3 when the sizes are not a multiple of 2
+    let first = invocation_id.xy*source_size/destination_size;
+    let last = min(((invocation_id.xy + 1u)*source_size + destination_size - 1u)/destination_size, source_size) - 1u;
+
+    var farthest = 1.0;
+    for (var y = first.y; y <= last.y; y = y + 1u) {
+        for (var x = first.x; x <= last.x; x = x + 1u) {
+            farthest = min(farthest, load_source(vec2<i32>(vec2<u32>(x, y))));
+        }
+    }
+    textureStore(destination, vec2<i32>(invocation_id.xy), vec4<f32>(farthest, 0.0, 0.0, 0.0));
+}
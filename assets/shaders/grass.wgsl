@@ -1,6 +1,11 @@
 
 #import bevy_pbr::mesh_types Mesh
-#import bevy_pbr::mesh_view_bindings
+#import bevy_pbr::mesh_view_bindings view, lights
+#import bevy_pbr::mesh_view_types DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT
+#import bevy_pbr::shadows as shadows
+#import bevy_pbr::utils PI
+#import bevy_core_pipeline::tonemapping screen_space_dither, powsafe, tone_mapping
+#import bevy_efficient_forest_rendering::wind Wind, wind_zone_push
 
 @group(1) @binding(0)
 var<uniform> mesh: Mesh;
@@ -103,7 +108,8 @@ fn perlinNoise3(P: vec3<f32>) -> f32 {
     chunk_half_extents: vec2<f32>,
     growth_texture_id: vec4<i32>,
     height_modifier: vec4<f32>,
-    scale_modifier: vec4<f32>
+    scale_modifier: vec4<f32>,
+    density_lod: vec4<f32>, //start distance, end distance, min density, nr_instances
  };
 
  @group(2) @binding(0)
@@ -126,10 +132,48 @@ var growth_sampler: sampler;
  struct GpuGridConfig {
     grid_center_xy: vec2<f32>, //Assume axis aligned grid otherwise need to calc homogenous coordinate matrix
     grid_half_extents: vec2<f32>,
+    height_range: vec4<f32>, //min height, max height, 1.0 if a height map is bound
 };
 
  @group(4) @binding(0)
  var<uniform> grid_config: GpuGridConfig;
+ @group(4) @binding(1)
+ var height_map: texture_2d<f32>;
+
+ struct GrassInteractor {
+    position_radius: vec4<f32>,
+    strength: vec4<f32>,
+ };
+
+ struct GrassInteractors {
+    count: vec4<u32>,
+    interactors: array<GrassInteractor, 64>, //MAX_GRASS_INTERACTORS
+ };
+
+ @group(4) @binding(2)
+ var<uniform> grass_interactors: GrassInteractors;
+
+ @group(4) @binding(3)
+ var<uniform> wind: Wind;
+
+//Terrain height under the uv, bilinear by hand since the height map might be a float format that cant be filtered
+fn terrain_height(uv: vec2<f32>) -> f32 {
+    if grid_config.height_range.z == 0.0 {
+        return 0.0;
+    }
+    let size = vec2<i32>(textureDimensions(height_map));
+    let texel = uv*vec2<f32>(size) - 0.5;
+    let base = floor(texel);
+    let fraction = texel - base;
+    let base_texel = vec2<i32>(base);
+    let max_texel = size - vec2<i32>(1, 1);
+    let h00 = textureLoad(height_map, clamp(base_texel, vec2<i32>(0, 0), max_texel), 0).x;
+    let h10 = textureLoad(height_map, clamp(base_texel + vec2<i32>(1, 0), vec2<i32>(0, 0), max_texel), 0).x;
+    let h01 = textureLoad(height_map, clamp(base_texel + vec2<i32>(0, 1), vec2<i32>(0, 0), max_texel), 0).x;
+    let h11 = textureLoad(height_map, clamp(base_texel + vec2<i32>(1, 1), vec2<i32>(0, 0), max_texel), 0).x;
+    let value = mix(mix(h00, h10, fraction.x), mix(h01, h11, fraction.x), fraction.y);
+    return mix(grid_config.height_range.x, grid_config.height_range.y, value);
+}
 
 struct Vertex {
     @location(0) position: vec3<f32>,
@@ -205,6 +249,8 @@ fn approximateSquareWave(t: f32, frequency: f32, numHarmonics: i32) -> f32 {
     return 4.0 / 3.1415 * squareWave;
 }
 
+const DENSITY_FADE: f32 = 0.1; //Part of the density over which a straw shrinks away, same as GRASS_DENSITY_FADE in chunk_grass.rs
+
 @vertex
 fn vertex(vertex: Vertex,
 ) -> VertexOutput {
@@ -222,11 +268,28 @@ fn vertex(vertex: Vertex,
     let base_position = vec4<f32>(x,y,0.0,1.0);
     let base_position_world = mesh_functions::mesh_position_local_to_world(mesh.model, base_position);
 
+    //Density lod, thin out with the horizontal camera distance. The position is random per instance index so the index works as the fade threshold,
+    //that way the straws the cpu cuts off the end of the instance range are the ones that would be faded out here anyway
+    let camera_distance = length(base_position_world.xy - view.world_position.xy);
+    let lod_t = clamp((camera_distance - material.density_lod.x)/max(material.density_lod.y - material.density_lod.x, 0.001), 0.0, 1.0);
+    let density = mix(1.0, material.density_lod.z, lod_t);
+    let rank = f32(vertex.instance_index)/material.density_lod.w;
+    let density_fade = clamp((density*(1.0 + DENSITY_FADE) - rank)/DENSITY_FADE, 0.0, 1.0);
+    if density_fade <= 0.0 {
+        out.clip_position = vec4<f32>(-2.0,-2.0,-2.0,-2.0);
+        return out;
+    }
+    //Fewer straws far away, make the ones left wider so the ground stays covered
+    let lod_width = 1.0/max(density, 0.01);
+
     //Random Rotate
     let rot_z = (rand1(v_index_float_fraction*0.412516)*2.0*3.1415);
     let rot_mat = mat2x2<f32>(vec2<f32>(cos(rot_z), -sin(rot_z)), vec2<f32>(sin(rot_z), cos(rot_z)));
-    let rotated_xy = rot_mat*vertex.position.xy*material.scale_modifier.x;
+    let rotated_xy = rot_mat*vertex.position.xy*material.scale_modifier.x*lod_width;
     let local_z = vertex.position.z*material.scale_modifier.x*material.height_modifier.x;
+    //Blade normal is rotated with the blade and then bent upwards so the field shades more like a soft surface than single flat straws
+    let rotated_normal = vec3<f32>(rot_mat*vertex.normal.xy, vertex.normal.z);
+    out.world_normal = normalize(mix(rotated_normal, vec3<f32>(0.0, 0.0, 1.0), 0.5));
     out.world_position= vec4<f32>(rotated_xy.x+base_position_world.x, rotated_xy.y+base_position_world.y, local_z+base_position_world.z, 1.0);
     
 
@@ -234,7 +297,8 @@ fn vertex(vertex: Vertex,
     let growth_uv = (base_position_world.xy-grid_config.grid_center_xy+grid_config.grid_half_extents)/(grid_config.grid_half_extents*2.0);
     out.uv = growth_uv; // out.uv = vertex.uv;
     let growth = textureSampleLevel(growth_textures,growth_sampler, growth_uv,material.growth_texture_id.x, 0.0).x;
-    out.world_position.z = out.world_position.z*growth;
+    //Straws about to be thinned out by the density lod shrink into the ground instead of popping
+    out.world_position.z = out.world_position.z*growth*density_fade;
 
     // "Hide" grass under map (This can be done better, probably by sampling 5x times and adjusting nr_instances based on texture sum over chunk)
     // Randomaly hide some in order to get a smooth transition from grass to ground
@@ -255,12 +319,16 @@ fn vertex(vertex: Vertex,
     out.world_position.x = out.world_position.x+noise_x;
     out.world_position.y = out.world_position.y+noise_y;
 
-    // Wind swing effect
-    let time_wave = sin(material.time / 1.0 + out.world_position.x/10.0);
-    out.world_position.x = out.world_position.x+time_wave*0.4*out.world_position.z;
+    // Wind swing effect, waves travel along the wind
+    let wind_dir = wind.direction_strength.xy;
+    let wind_side = vec2<f32>(wind_dir.y, -wind_dir.x);
+    let time_wave = sin(material.time / 1.0 - dot(out.world_position.xy, wind_dir)/10.0);
+    let swing = -time_wave*wind.direction_strength.z*out.world_position.z;
+    out.world_position.x = out.world_position.x+wind_dir.x*swing;
+    out.world_position.y = out.world_position.y+wind_dir.y*swing;
 
     //Grass turbulance effect
-    var freq = 2.0;
+    var freq = wind.gust_turbulence.z;
     let time_wave_x = cos(material.time * freq + out.world_position.x);
     let time_wave_y = sin(material.time * freq + out.world_position.y);
     var amp = .1*out.world_position.z;
@@ -279,16 +347,18 @@ fn vertex(vertex: Vertex,
 
 
 
-    //Grass gust effect in x direction
-    var freq_gust_speed = 0.7; //Higher value = faster gust
+    //Grass gust effect in the wind direction
+    var freq_gust_speed = wind.gust_turbulence.y; //Higher value = faster gust
     var freq_gust_amp = 0.5; //Higher value = faster toggle between gust and no gust
     var freq_gust_shape = 0.3; //Determines the speed of change of the islands shapes.
-    var gust_amp = 1.4*(sin(material.time*freq_gust_amp)+1.0)+0.1; //Higher value = stronger gust
-    var gust_perl_freq = 0.05; //Determines the size of the gust islands, higher value = smaller islands, also effects the speed of the gust
+    var gust_amp = (1.4*(sin(material.time*freq_gust_amp)+1.0)+0.1)*wind.direction_strength.w; //Higher value = stronger gust
+    var gust_perl_freq = wind.gust_turbulence.x; //Determines the size of the gust islands, higher value = smaller islands, also effects the speed of the gust
     let gust_time_wave = material.time * freq_gust_speed;
+    //Noise is sampled in wind space so the islands travel with the wind
+    let gust_uv = vec2<f32>(-dot(out.world_position.xy, wind_dir), dot(out.world_position.xy, wind_side));
     var perl_noise_gust = perlinNoise3(
-        vec3<f32>(out.world_position.x*gust_perl_freq+gust_time_wave, 
-        out.world_position.y*gust_perl_freq, 
+        vec3<f32>(gust_uv.x*gust_perl_freq+gust_time_wave, 
+        gust_uv.y*gust_perl_freq, 
         abs(sin(material.time*freq_gust_shape))*0.9+0.1));    // Determines the shape of the gust islands over time
     perl_noise_gust = (perl_noise_gust - 0.4)/2.0*gust_amp; // Clips values in order to create islands from perlin noise
     if (perl_noise_gust<0.0){
@@ -300,11 +370,38 @@ fn vertex(vertex: Vertex,
         perl_noise_gust = local_z;
     }
 
-    out.world_position = vec4<f32>(out.world_position.x-perl_noise_gust, out.world_position.y, out.world_position.z, out.world_position.w);
+    out.world_position = vec4<f32>(out.world_position.xy+wind_dir*perl_noise_gust, out.world_position.z, out.world_position.w);
     // Visualize gust effect
     // out.world_position = vec4<f32>(out.world_position.x, out.world_position.y, perl_noise_gust, out.world_position.w);
 
 
+    //Bend away from interactors (animals, the player, trails they left), ignoring the ones flying high above the grass.
+    //Wind zones push the same way, shaking a bit
+    let terrain = terrain_height(growth_uv);
+    let ground = base_position_world.z + terrain;
+    var push = vec2<f32>(0.0, 0.0);
+    for (var i = 0u; i < grass_interactors.count.x; i = i + 1u) {
+        let interactor = grass_interactors.interactors[i];
+        let radius = interactor.position_radius.w;
+        let offset = base_position_world.xy - interactor.position_radius.xy;
+        let distance = length(offset);
+        if distance < radius && abs(interactor.position_radius.z - ground) < radius + local_z {
+            push = push + offset/max(distance, 0.001)*(1.0 - distance/radius)*interactor.strength.x;
+        }
+    }
+    let shake = 0.8 + 0.2*sin(material.time*8.0 + rand1(v_index_float_fraction*0.3141)*6.2831);
+    for (var i = 0u; i < wind.zone_count.x; i = i + 1u) {
+        push = push + wind_zone_push(wind.zones[i], vec3<f32>(base_position_world.xy, ground))*shake;
+    }
+    let push_amount = min(length(push), 1.0);
+    if push_amount > 0.0 {
+        let push_dir = normalize(push);
+        out.world_position.x = out.world_position.x + push_dir.x*push_amount*out.world_position.z;
+        out.world_position.y = out.world_position.y + push_dir.y*push_amount*out.world_position.z;
+        out.world_position.z = out.world_position.z*(1.0 - 0.7*push_amount);
+    }
+
+
 
     //Color
     let tip_color = mix(material.unhealthy_tip_color, material.healthy_tip_color, growth);
@@ -323,14 +420,83 @@ fn vertex(vertex: Vertex,
 
     out.color.z = out.color.z+rand1(v_index_float_fraction*0.12319217)*0.1;
 
+    //Lift the straw onto the terrain last, the wind and growth above all work on the height over the ground
+    out.world_position.z = out.world_position.z + terrain;
+
     out.clip_position = mesh_functions::mesh_position_world_to_clip(out.world_position);
 
     return out;
 }
 
 
+#ifdef GRASS_LIT
+// Lighting tweaks for the lit grass
+const GRASS_WRAP: f32 = 0.5; //How far the diffuse light wraps around the blade, 0 = lambert
+const GRASS_TRANSLUCENCY: f32 = 0.6; //Light shining through the blades when looking towards the sun
+const GRASS_SHEEN: f32 = 0.02; //Strength of the specular sheen
+const GRASS_SHEEN_POWER: f32 = 16.0; //Higher value = smaller sheen highlight
+
+fn lit_grass(in: VertexOutput) -> vec4<f32> {
+    let V = normalize(view.world_position.xyz - in.world_position.xyz);
+    var N = normalize(in.world_normal);
+    //Blades are two sided, flip the normal towards the camera
+    if (dot(N, V) < 0.0){
+        N = -N;
+    }
+
+    //Needed to pick the shadow cascade
+    let view_z = dot(vec4<f32>(
+        view.inverse_view[0].z,
+        view.inverse_view[1].z,
+        view.inverse_view[2].z,
+        view.inverse_view[3].z
+    ), in.world_position);
+
+    var diffuse_light = lights.ambient_color.rgb;
+    var specular_light = vec3<f32>(0.0);
+    for (var i: u32 = 0u; i < lights.n_directional_lights; i = i + 1u) {
+        let light = &lights.directional_lights[i];
+        let L = (*light).direction_to_light;
+
+        var shadow = 1.0;
+        if (((*light).flags & DIRECTIONAL_LIGHT_FLAGS_SHADOWS_ENABLED_BIT) != 0u) {
+            //Use up as the surface normal for the bias, the blade normal flips around too much
+            shadow = shadows::fetch_directional_shadow(i, in.world_position, vec3<f32>(0.0, 0.0, 1.0), view_z);
+        }
+
+        let wrap_diffuse = max((dot(N, L) + GRASS_WRAP) / (1.0 + GRASS_WRAP), 0.0);
+        let translucency = pow(max(dot(V, -L), 0.0), 4.0) * GRASS_TRANSLUCENCY;
+        let H = normalize(L + V);
+        let sheen = pow(max(dot(N, H), 0.0), GRASS_SHEEN_POWER) * GRASS_SHEEN;
+
+        diffuse_light = diffuse_light + (*light).color.rgb * shadow * (wrap_diffuse + translucency) / PI;
+        specular_light = specular_light + (*light).color.rgb * shadow * sheen;
+    }
+
+    var output_color = vec4<f32>(in.color.rgb * diffuse_light + specular_light, in.color.a);
+
+#ifdef TONEMAP_IN_SHADER
+    output_color = tone_mapping(output_color, view.color_grading);
+#ifdef DEBAND_DITHER
+    var output_rgb = output_color.rgb;
+    output_rgb = powsafe(output_rgb, 1.0 / 2.2);
+    output_rgb = output_rgb + screen_space_dither(in.clip_position.xy);
+    // This conversion back to linear space is required because our output texture format is
+    // SRGB; the GPU will assume our output is linear and will apply an SRGB conversion.
+    output_rgb = powsafe(output_rgb, 2.2);
+    output_color = vec4(output_rgb, output_color.a);
+#endif
+#endif
+    return output_color;
+}
+#endif
+
 @fragment
 fn fragment(in: VertexOutput) -> @location(0) vec4<f32> {
     // return  vec4<f32>(0.5,0.5,0.5,1.0);
+#ifdef GRASS_LIT
+    return lit_grass(in);
+#else
     return in.color;
+#endif
 }
\ No newline at end of file
@@ -0,0 +1,20 @@
+#import bevy_efficient_forest_rendering::occlusion as occlusion
+
+// Hides a whole grass chunk when it is occluded, the grass itself has no per straw data to test
+
+struct GrassCullingData {
+    chunk_min: vec4<f32>, // world space bounds of the chunk including the grass height
+    chunk_max: vec4<f32>,
+};
+
+@group(0) @binding(0)
+var<uniform> grass_culling: GrassCullingData;
+@group(0) @binding(1)
+var<storage, read_write> indirect_args: array<u32, 5>;
+
+@compute @workgroup_size(1, 1, 1)
+fn cull() {
+    if (occlusion::is_occluded(grass_culling.chunk_min.xyz, grass_culling.chunk_max.xyz)) {
+        indirect_args[1] = 0u;
+    }
+}
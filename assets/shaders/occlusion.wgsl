@@ -0,0 +1,59 @@
+#define_import_path bevy_efficient_forest_rendering::occlusion
+
+// Occlusion test against the depth pyramid of the previous frame.
+// Things that were hidden last frame but got uncovered by camera movement pop in one frame late.
+
+struct OcclusionData {
+    view_proj: mat4x4<f32>, // view projection the pyramid was rendered with
+    pyramid_size: vec2<f32>, // size of the first level
+    mip_count: u32,
+    enabled: u32, // 0 until the first pyramid has been built
+};
+
+@group(1) @binding(0)
+var<uniform> occlusion: OcclusionData;
+@group(1) @binding(1)
+var depth_pyramid: texture_2d<f32>;
+
+// True if the world space box is behind everything that was drawn where it ends up on the screen
+fn is_occluded(world_min: vec3<f32>, world_max: vec3<f32>) -> bool {
+    if (occlusion.enabled == 0u) {
+        return false;
+    }
+
+    var uv_min = vec2<f32>(1.0);
+    var uv_max = vec2<f32>(0.0);
+    var nearest_depth = 0.0;
+    for (var i = 0u; i < 8u; i = i + 1u) {
+        let corner = select(world_min, world_max, vec3<bool>((i & 1u) != 0u, (i & 2u) != 0u, (i & 4u) != 0u));
+        let clip = occlusion.view_proj*vec4<f32>(corner, 1.0);
+        // Crosses the camera plane, can't say anything about it
+        if (clip.w <= 0.0) {
+            return false;
+        }
+        let ndc = clip.xyz/clip.w;
+        let uv = ndc.xy*vec2<f32>(0.5, -0.5) + 0.5;
+        uv_min = min(uv_min, uv);
+        uv_max = max(uv_max, uv);
+        nearest_depth = max(nearest_depth, ndc.z); // reverse z
+    }
+    // Off screen last frame, leave it to the frustum culling
+    if (any(uv_max < vec2<f32>(0.0)) || any(uv_min > vec2<f32>(1.0))) {
+        return false;
+    }
+    uv_min = clamp(uv_min, vec2<f32>(0.0), vec2<f32>(1.0));
+    uv_max = clamp(uv_max, vec2<f32>(0.0), vec2<f32>(1.0));
+
+    // Pick the level where the box covers at most 2x2 texels
+    let size = (uv_max - uv_min)*occlusion.pyramid_size;
+    let level = min(u32(ceil(log2(max(max(size.x, size.y), 1.0)))), occlusion.mip_count - 1u);
+    let level_size = vec2<i32>(textureDimensions(depth_pyramid, i32(level)));
+    let texel_min = min(vec2<i32>(uv_min*vec2<f32>(level_size)), level_size - 1);
+    let texel_max = min(vec2<i32>(uv_max*vec2<f32>(level_size)), level_size - 1);
+
+    let farthest = min(
+        min(textureLoad(depth_pyramid, texel_min, i32(level)).x, textureLoad(depth_pyramid, vec2<i32>(texel_max.x, texel_min.y), i32(level)).x),
+        min(textureLoad(depth_pyramid, vec2<i32>(texel_min.x, texel_max.y), i32(level)).x, textureLoad(depth_pyramid, texel_max, i32(level)).x),
+    );
+    return nearest_depth < farthest;
+}
@@ -0,0 +1,36 @@
+#define_import_path bevy_efficient_forest_rendering::wind
+
+// Shared by the grass and the instanced plants, see wind.rs
+
+struct WindZone {
+    position_radius: vec4<f32>,
+    direction_strength: vec4<f32>, // direction xy (zero for spherical zones), strength, falloff
+};
+
+struct Wind {
+    direction_strength: vec4<f32>, // direction xy (normalized), strength, gustiness
+    gust_turbulence: vec4<f32>, // gust scale, gust speed, turbulence
+    zone_count: vec4<u32>,
+    zones: array<WindZone, 16>, // MAX_WIND_ZONES
+};
+
+// Horizontal push of a wind zone at a world position, spherical zones push away from their center
+fn wind_zone_push(zone: WindZone, position: vec3<f32>) -> vec2<f32> {
+    let offset = position - zone.position_radius.xyz;
+    let distance = length(offset);
+    let radius = zone.position_radius.w;
+    if (distance >= radius) {
+        return vec2<f32>(0.0, 0.0);
+    }
+    let strength = zone.direction_strength.z*pow(1.0 - distance/radius, zone.direction_strength.w);
+    var direction = zone.direction_strength.xy;
+    if (dot(direction, direction) == 0.0) {
+        let horizontal_distance = length(offset.xy);
+        // Straight below the center there is no outwards
+        if (horizontal_distance < 0.001) {
+            return vec2<f32>(0.0, 0.0);
+        }
+        direction = offset.xy/horizontal_distance;
+    }
+    return direction*strength;
+}
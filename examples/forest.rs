@@ -1,10 +1,11 @@
-use std::time::Duration;
+use std::{sync::Arc, time::Duration};
 
 use bevy::{
     asset::ChangeWatcher,
     gltf::{Gltf, GltfMesh},
     math::prelude::*,
     math::Vec3A,
+    pbr::CascadeShadowConfigBuilder,
     prelude::*,
     render::{
         mesh::VertexAttributeValues,
@@ -19,10 +20,14 @@ use itertools::Itertools;
 
 use bevy_efficient_forest_rendering::rendering::{
     chunk_grass::{
-        get_grass_straw_mesh, ChunkGrass, ChunkGrassBundle, ChunkGrassPlugin, GridConfig,
-        GrowthTextures,
+        get_grass_straw_mesh, ChunkGrass, ChunkGrassBundle, ChunkGrassPlugin, GrassDensityLod,
+        GrassLighting, GridConfig, GrowthTextures,
     },
-    chunk_instancing::{ChunkInstancing, ChunkInstancingBundle, ChunkInstancingPlugin},
+    chunk_instancing::{
+        get_impostor_mesh, ChunkInstancing, ChunkInstancingBundle, ChunkInstancingPlugin, ChunkLod,
+        Impostor,
+    },
+    scatter::{DensityMap, Scatter},
     Chunk, DistanceCulling,
 };
 
@@ -72,6 +77,7 @@ pub struct MyImageAssets {
 const NR_SIDE_CHUNKS: u32 = 20;
 const INSTANCE_DENSITY: i32 = 1; //4
 const CHUNK_SIZE: f32 = 30.;
+const WORLD_SEED: u64 = 1337; //Same seed gives the same forest every run
 
 fn main() {
     let mut app = App::new();
@@ -105,9 +111,19 @@ fn main() {
         .add_plugins(ChunkInstancingPlugin)
         .add_plugins(ChunkGrassPlugin)
         .add_plugins(HelpersPlugin)
-        .add_systems(OnEnter(GameState::InGame), setup_ground_grass)
-        .add_systems(OnEnter(GameState::InGame), setup_plants)
-        .run();
+        //Plants are placed from the growth textures, so the grass setup goes first
+        .add_systems(
+            OnEnter(GameState::InGame),
+            (setup_ground_grass, setup_plants).chain(),
+        );
+
+    //Compute shaders are not available with webgl2
+    #[cfg(not(target_family = "wasm"))]
+    app.add_plugins(
+        bevy_efficient_forest_rendering::rendering::gpu_culling::GpuInstanceCullingPlugin,
+    );
+
+    app.run();
 }
 
 fn setup_ground_grass(
@@ -127,9 +143,18 @@ fn setup_ground_grass(
     commands.spawn(DirectionalLightBundle {
         directional_light: DirectionalLight {
             illuminance: 30000.0,
-            shadows_enabled: false, //Weird things happen
+            shadows_enabled: true,
             ..default()
         },
+        //Tilted a bit so the trees cast visible shadows (z is up)
+        transform: Transform::default().looking_to(Vec3::new(0.5, 0.3, -1.0), Vec3::Z),
+        //Only the area around the camera needs shadows, the rest is culled anyway
+        cascade_shadow_config: CascadeShadowConfigBuilder {
+            first_cascade_far_bound: 30.0,
+            maximum_distance: 300.0,
+            ..default()
+        }
+        .into(),
         ..default()
     });
 
@@ -140,6 +165,7 @@ fn setup_ground_grass(
             NR_SIDE_CHUNKS as f32 * CHUNK_SIZE / 2.0,
             NR_SIDE_CHUNKS as f32 * CHUNK_SIZE / 2.0,
         ],
+        ..default()
     };
 
     //Growth Textures
@@ -216,7 +242,7 @@ fn setup_ground_grass(
             chunk_xy: [chunk_x, chunk_y],
         };
 
-        commands.spawn(ChunkGrassBundle {
+        let mut grass = commands.spawn(ChunkGrassBundle {
             transform: Transform::from_xyz(chunk_x_pos, chunk_y_pos, 0.0),
             mesh_handle: meshes.add(get_grass_straw_mesh()),
             aabb: Aabb {
@@ -240,11 +266,14 @@ fn setup_ground_grass(
                 growth_texture_id: 1,
                 scale: 1.6,
                 height_modifier: 0.6,
+                lighting: GrassLighting::Lit,
+                transparent: false,
             },
             chunk: chunk.clone(),
             distance_culling: DistanceCulling { distance: 300.0 },
             ..default()
         });
+        grass.insert(GrassDensityLod::new(25.0, 280.0, 0.1)); //Thin out on the way to the culling distance
         tot_instances_grass += nr_instances * 50;
     }
     info!("Total grass straws {:?}", tot_instances_grass);
@@ -274,53 +303,78 @@ fn setup_plants(
     gltf_meshes: Res<Assets<GltfMesh>>,
     assets_gltf: Res<Assets<Gltf>>,
     my_gltf_assets: Res<MyGltfAssets>,
-    materials: Res<Assets<StandardMaterial>>,
+    grid_config: Res<GridConfig>,
+    growth_texture: Res<GrowthTextures>,
+    images: Res<Assets<Image>>,
+    mut meshes: ResMut<Assets<Mesh>>,
+    mut materials: ResMut<Assets<StandardMaterial>>,
 ) {
-    //Load all models and textures (There has to be a better way than this?)
+    //Load all models and materials (There has to be a better way than this?)
     let mushroom_gltf = assets_gltf.get(&my_gltf_assets.mushroom).unwrap();
     let mushroom_primitive = &gltf_meshes
         .get(&mushroom_gltf.meshes[0])
         .unwrap()
         .primitives[0];
     let mushroom_mesh_handle = mushroom_primitive.mesh.clone();
-    let mushroom_texture = materials
-        .get(&mushroom_primitive.material.clone().unwrap())
-        .unwrap()
-        .base_color_texture
-        .clone()
-        .unwrap();
+    let mushroom_material = mushroom_primitive.material.clone().unwrap();
 
     let tree_gltf = assets_gltf.get(&my_gltf_assets.tree).unwrap();
     let tree_primitive = &gltf_meshes.get(&tree_gltf.meshes[0]).unwrap().primitives[0];
     let tree_mesh_handle = tree_primitive.mesh.clone();
-    let tree_texture = materials
-        .get(&tree_primitive.material.clone().unwrap())
+    let tree_material = tree_primitive.material.clone().unwrap();
+
+    //Far away trees are drawn as flat camera facing quads of the same size
+    let tree_aabb = meshes
+        .get(&tree_mesh_handle)
         .unwrap()
-        .base_color_texture
-        .clone()
+        .compute_aabb()
         .unwrap();
+    let tree_lod = ChunkLod::default()
+        .with_level(tree_mesh_handle.clone(), 250.0)
+        .with_impostor(Impostor {
+            mesh: meshes.add(get_impostor_mesh(
+                tree_aabb.half_extents.x * 2.0,
+                tree_aabb.max().z,
+            )),
+            material: materials.add(StandardMaterial {
+                base_color: Color::rgb(0.15, 0.3, 0.1),
+                perceptual_roughness: 1.0,
+                ..default()
+            }),
+            distance: 250.0,
+            atlas_columns: 1,
+        });
 
     let rock_gltf = assets_gltf.get(&my_gltf_assets.rock).unwrap();
     let rock_primitive = &gltf_meshes.get(&rock_gltf.meshes[0]).unwrap().primitives[0];
     let rock_mesh_handle = rock_primitive.mesh.clone();
-    let rock_texture = materials
-        .get(&rock_primitive.material.clone().unwrap())
-        .unwrap()
-        .base_color_texture
-        .clone()
-        .unwrap();
+    let rock_material = rock_primitive.material.clone().unwrap();
 
     let bush_gltf = assets_gltf.get(&my_gltf_assets.bush).unwrap();
     let bush_primitive = &gltf_meshes.get(&bush_gltf.meshes[0]).unwrap().primitives[0];
     let bush_mesh_handle = bush_primitive.mesh.clone();
-    let bush_texture = materials
-        .get(&bush_primitive.material.clone().unwrap())
-        .unwrap()
-        .base_color_texture
-        .clone()
+    let bush_material = bush_primitive.material.clone().unwrap();
+
+    //Forests and clearings follow the first growth texture, the grass uses the second one
+    let growth_image = growth_texture
+        .growth_texture_array_handle
+        .as_ref()
+        .and_then(|handle| images.get(handle))
         .unwrap();
+    let forest_density = Arc::new(DensityMap::from_image(growth_image, 0, &grid_config).unwrap());
+    let world_origin = Vec2::splat(-CHUNK_SIZE * NR_SIDE_CHUNKS as f32 / 2.0);
 
     let nr_instances = (CHUNK_SIZE * CHUNK_SIZE * INSTANCE_DENSITY as f32) as u32;
+    //Trees are spaced out first, bushes and rocks keep clear of the trunks
+    let tree_scatter = Scatter::new(1, 3.0)
+        .with_max_instances(nr_instances as usize / 15)
+        .with_density_map(forest_density.clone(), world_origin);
+    let bush_scatter = Scatter::new(2, 1.2)
+        .with_max_instances(nr_instances as usize / 6)
+        .excluding(&tree_scatter, 1.0);
+    let rock_scatter = Scatter::new(3, 1.5)
+        .with_max_instances(nr_instances as usize / 10)
+        .excluding(&tree_scatter, 1.0);
     let mut tot_instances = 0;
     for (chunk_x, chunk_y) in (0..NR_SIDE_CHUNKS).cartesian_product(0..NR_SIDE_CHUNKS) {
         let chunk_x_pos = chunk_x as f32 * CHUNK_SIZE - CHUNK_SIZE * NR_SIDE_CHUNKS as f32 / 2.0;
@@ -336,37 +390,46 @@ fn setup_plants(
                 center: Vec3A::ZERO,
                 half_extents: Vec3A::new(CHUNK_SIZE, CHUNK_SIZE, 0.0), //Why do I need full chunk_size here?!
             },
-            chunk_instancing: ChunkInstancing::new(
+            chunk_instancing: ChunkInstancing::new_seeded(
                 nr_instances / 5,
-                mushroom_texture.clone(),
+                mushroom_material.clone(),
                 Transform::from_rotation(Quat::from_rotation_x(90_f32.to_radians()))
                     .with_scale(Vec3::splat(0.05)),
                 CHUNK_SIZE,
-            ),
+                WORLD_SEED,
+                &chunk,
+                0,
+            )
+            .with_density_map(&forest_density, Vec2::new(chunk_x_pos, chunk_y_pos))
+            .with_stiffness(0.9),
             chunk: chunk.clone(),
             distance_culling: DistanceCulling { distance: 100.0 },
             ..default()
         });
         tot_instances += nr_instances / 5;
 
-        commands.spawn(ChunkInstancingBundle {
+        let mut trees = commands.spawn(ChunkInstancingBundle {
             transform: Transform::from_xyz(chunk_x_pos, chunk_y_pos, 0.0),
             mesh_handle: tree_mesh_handle.clone(),
             aabb: Aabb {
                 center: Vec3A::ZERO,
                 half_extents: Vec3A::new(CHUNK_SIZE, CHUNK_SIZE, 0.0), //Why do I need full chunk_size here?!
             },
-            chunk_instancing: ChunkInstancing::new(
-                nr_instances / 15,
-                tree_texture.clone(),
+            chunk_instancing: ChunkInstancing::new_scattered(
+                &tree_scatter,
+                tree_material.clone(),
                 Transform::from_rotation(Quat::from_rotation_x(0_f32.to_radians()))
                     .with_scale(Vec3::splat(0.2)),
                 CHUNK_SIZE,
-            ),
+                WORLD_SEED,
+                &chunk,
+            )
+            .with_stiffness(0.6),
             chunk: chunk.clone(),
             distance_culling: DistanceCulling { distance: 600.0 },
             ..default()
         });
+        trees.insert(tree_lod.clone());
         tot_instances += nr_instances / 15;
 
         commands.spawn(ChunkInstancingBundle {
@@ -376,13 +439,17 @@ fn setup_plants(
                 center: Vec3A::ZERO,
                 half_extents: Vec3A::new(CHUNK_SIZE, CHUNK_SIZE, 0.0), //Why do I need full chunk_size here?!
             },
-            chunk_instancing: ChunkInstancing::new(
-                nr_instances / 6,
-                bush_texture.clone(),
+            chunk_instancing: ChunkInstancing::new_scattered(
+                &bush_scatter,
+                bush_material.clone(),
                 Transform::from_rotation(Quat::from_rotation_x(0_f32.to_radians()))
                     .with_scale(Vec3::splat(0.4)),
                 CHUNK_SIZE,
-            ),
+                WORLD_SEED,
+                &chunk,
+            )
+            .with_random_tint(Color::rgb(0.75, 0.8, 0.7), Color::rgb(1.0, 1.0, 0.9))
+            .with_stiffness(0.3),
             chunk: chunk.clone(),
             distance_culling: DistanceCulling { distance: 200.0 },
             ..default()
@@ -396,12 +463,14 @@ fn setup_plants(
                 center: Vec3A::ZERO,
                 half_extents: Vec3A::new(CHUNK_SIZE, CHUNK_SIZE, 0.0), //Why do I need full chunk_size here?!
             },
-            chunk_instancing: ChunkInstancing::new(
-                nr_instances / 10,
-                rock_texture.clone(),
+            chunk_instancing: ChunkInstancing::new_scattered(
+                &rock_scatter,
+                rock_material.clone(),
                 Transform::from_rotation(Quat::from_rotation_x(0_f32.to_radians()))
                     .with_scale(Vec3::splat(0.6)),
                 CHUNK_SIZE,
+                WORLD_SEED,
+                &chunk,
             ),
             chunk: chunk.clone(),
             distance_culling: DistanceCulling { distance: 200.0 },
@@ -18,8 +18,8 @@ use itertools::Itertools;
 
 use bevy_efficient_forest_rendering::rendering::{
     chunk_grass::{
-        get_grass_straw_mesh, ChunkGrass, ChunkGrassBundle, ChunkGrassPlugin, GridConfig,
-        GrowthTextures,
+        get_grass_straw_mesh, ChunkGrass, ChunkGrassBundle, ChunkGrassPlugin, GrassLighting,
+        GridConfig, GrowthTextures,
     },
     Chunk, DistanceCulling,
 };
@@ -122,6 +122,7 @@ fn setup_ground_grass(
             NR_SIDE_CHUNKS as f32 * CHUNK_SIZE / 2.0,
             NR_SIDE_CHUNKS as f32 * CHUNK_SIZE / 2.0,
         ],
+        ..default()
     };
 
     //Growth Textures
@@ -220,6 +221,8 @@ fn setup_ground_grass(
                 growth_texture_id: 1,
                 scale: 1.6,
                 height_modifier: 1.4,
+                lighting: GrassLighting::Unlit,
+                transparent: false,
             },
             chunk: chunk.clone(),
             distance_culling: DistanceCulling { distance: 300.0 },
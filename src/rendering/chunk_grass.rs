@@ -1,11 +1,17 @@
 use bevy::{
-    core_pipeline::core_3d::Transparent3d,
+    core_pipeline::{
+        core_3d::{Opaque3d, Transparent3d},
+        tonemapping::{DebandDither, Tonemapping},
+    },
     ecs::{
         query::ROQueryItem,
         system::{lifetimeless::*, SystemParamItem},
     },
     math::prelude::*,
-    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    pbr::{
+        EnvironmentMapLight, MeshPipeline, MeshPipelineKey, MeshUniform,
+        ScreenSpaceAmbientOcclusionSettings, SetMeshBindGroup, SetMeshViewBindGroup,
+    },
     prelude::*,
     reflect::TypeUuid,
     render::{
@@ -19,23 +25,30 @@ use bevy::{
             RenderPhase, SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
-        texture::ImageSampler,
+        renderer::{RenderDevice, RenderQueue},
+        texture::{FallbackImage, ImageSampler},
         view::{ExtractedView, Msaa},
         Render,
     },
     render::{
         extract_component::ExtractComponentPlugin,
         mesh::Indices,
-        render_resource::{PrimitiveTopology, ShaderType, SpecializedMeshPipelines},
+        render_resource::{PrimitiveTopology, SpecializedMeshPipelines},
         RenderApp, RenderSet,
     },
+    transform::TransformSystem,
+    utils::{HashMap, HashSet},
 };
 use bytemuck::{Pod, Zeroable};
 
 use noise::{NoiseFn, Perlin};
 
-use super::{Chunk, DistanceCulling};
+use super::{
+    gpu_culling::OccludedGrassBuffers,
+    pbr_view_key,
+    wind::{WindBuffer, WindPlugin},
+    Chunk, DistanceCulling,
+};
 
 //Bundle
 #[derive(Bundle, Debug, Default)]
@@ -61,6 +74,92 @@ fn update_time_for_custom_material(mut grass_chunks: Query<&mut ChunkGrass>, tim
     }
 }
 
+fn update_grass_interactors(
+    mut grass_interactors: ResMut<GrassInteractors>,
+    interactors: Query<(Entity, &GlobalTransform, &GrassInteractor)>,
+    time: Res<Time>,
+    mut warned: Local<bool>,
+) {
+    let grass_interactors = grass_interactors.as_mut();
+    grass_interactors.points.clear();
+
+    //Trampled grass slowly recovers
+    for trail_point in grass_interactors.trail.iter_mut() {
+        trail_point.time_left -= time.delta_seconds();
+    }
+    grass_interactors
+        .trail
+        .retain(|trail_point| trail_point.time_left > 0.0);
+
+    //Live interactors always fit, the trail gets what is left
+    let trail_budget = MAX_GRASS_INTERACTORS.saturating_sub(interactors.iter().len());
+    //Trail points get spread out twice as far once the trail uses more than half of its budget
+    let spacing = if grass_interactors.trail.len() > trail_budget / 2 {
+        1.0
+    } else {
+        0.5
+    };
+
+    for (entity, transform, interactor) in &interactors {
+        let point = InteractorPoint {
+            position: transform.translation(),
+            radius: interactor.radius,
+            strength: interactor.strength,
+        };
+        grass_interactors.points.push(point);
+
+        //Drop a new trail point every half radius walked, or every radius when the trail is getting full
+        let Some(recovery) = interactor.trail_recovery else {
+            continue;
+        };
+        let moved_far_enough = match grass_interactors.last_trail_point.get(&entity) {
+            Some(last) => last.distance(point.position) > interactor.radius * spacing,
+            None => true,
+        };
+        if moved_far_enough {
+            grass_interactors.trail.push(TrailPoint {
+                point,
+                time_left: recovery,
+                recovery,
+            });
+            grass_interactors
+                .last_trail_point
+                .insert(entity, point.position);
+        }
+    }
+    let alive: HashSet<Entity> = interactors.iter().map(|(entity, ..)| entity).collect();
+    grass_interactors
+        .last_trail_point
+        .retain(|entity, _| alive.contains(entity));
+
+    //Strongest trail points first so the ones that have almost recovered are the ones dropped
+    grass_interactors
+        .trail
+        .sort_by(|a, b| b.current_strength().total_cmp(&a.current_strength()));
+    if grass_interactors.trail.len() > trail_budget {
+        if !*warned {
+            warn!(
+                "More than {} grass interactors and trail points, the weakest trail points are dropped",
+                MAX_GRASS_INTERACTORS
+            );
+            *warned = true;
+        }
+        grass_interactors.trail.truncate(trail_budget);
+    }
+    grass_interactors
+        .points
+        .extend(
+            grass_interactors
+                .trail
+                .iter()
+                .map(|trail_point| InteractorPoint {
+                    strength: trail_point.current_strength(),
+                    ..trail_point.point
+                }),
+        );
+    grass_interactors.points.truncate(MAX_GRASS_INTERACTORS);
+}
+
 fn grass_chunk_distance_culling(
     mut query: Query<(&Transform, &mut Visibility, &DistanceCulling)>,
     query_camera: Query<&Transform, With<Camera>>,
@@ -114,12 +213,23 @@ pub struct ChunkGrassPlugin;
 impl Plugin for ChunkGrassPlugin {
     fn build(&self, app: &mut App) {
         app.add_plugins(ExtractComponentPlugin::<ChunkGrass>::extract_visible());
+        app.add_plugins(ExtractComponentPlugin::<GrassDensityLod>::extract_visible());
         app.add_plugins(ExtractResourcePlugin::<GrowthTextures>::default());
         app.add_plugins(ExtractResourcePlugin::<GridConfig>::default());
         app.insert_resource(GridConfig::default());
         app.insert_resource(GrowthTextures::default());
         app.add_systems(Update, update_time_for_custom_material);
         app.add_systems(Update, grass_chunk_distance_culling);
+        app.add_systems(First, clear_painted_growth);
+        app.add_plugins(ExtractResourcePlugin::<GrassInteractors>::default());
+        if !app.is_plugin_added::<WindPlugin>() {
+            app.add_plugins(WindPlugin);
+        }
+        app.init_resource::<GrassInteractors>();
+        app.add_systems(
+            PostUpdate,
+            update_grass_interactors.after(TransformSystem::TransformPropagate),
+        );
 
         let render_app = match app.get_sub_app_mut(RenderApp) {
             Ok(render_app) => render_app,
@@ -127,6 +237,7 @@ impl Plugin for ChunkGrassPlugin {
         };
 
         render_app
+            .add_render_command::<Opaque3d, DrawCustom>()
             .add_render_command::<Transparent3d, DrawCustom>()
             .init_resource::<SpecializedMeshPipelines<CustomPipeline>>()
             .init_resource::<GridConfigBindGroup>()
@@ -143,7 +254,9 @@ impl Plugin for ChunkGrassPlugin {
             .add_systems(
                 Render,
                 prepare_growth_textures_bind_group.in_set(RenderSet::Prepare),
-            );
+            )
+            .add_systems(Render, upload_painted_growth.in_set(RenderSet::Prepare))
+            .add_systems(Render, prepare_grass_interactors.in_set(RenderSet::Prepare));
     }
 
     fn finish(&self, app: &mut App) {
@@ -159,6 +272,26 @@ impl Plugin for ChunkGrassPlugin {
 #[derive(Clone, Component, Default, Resource)]
 pub struct GrowthTextures {
     pub growth_texture_array_handle: Option<Handle<Image>>,
+    //Painted copy of the texture array, the image asset itself is left alone since touching it re-uploads the whole array
+    painted: Option<PaintedGrowth>,
+    uploads: Vec<GrowthTextureUpload>, //Only filled in the render world
+}
+
+#[derive(Clone)]
+struct PaintedGrowth {
+    size: UVec2,
+    layers: u32,
+    data: Vec<u8>,
+    dirty: Vec<Option<(UVec2, UVec2)>>, //Per layer, min and max texel painted since the last upload
+}
+
+//Rectangle of one layer that is copied to the gpu texture
+#[derive(Clone)]
+struct GrowthTextureUpload {
+    layer: u32,
+    origin: UVec2,
+    size: UVec2,
+    data: Vec<u8>,
 }
 
 impl GrowthTextures {
@@ -195,14 +328,127 @@ impl GrowthTextures {
 
         Self {
             growth_texture_array_handle: Some(images.add(image)),
+            ..default()
+        }
+    }
+
+    //Sets the growth of every texel within radius of world_xy, e.g 0.0 to remove grass that got grazed or burned.
+    //Only the painted part of the layer is uploaded to the gpu at the end of the frame
+    pub fn paint_circle(
+        &mut self,
+        images: &Assets<Image>,
+        grid_config: &GridConfig,
+        world_xy: Vec2,
+        radius: f32,
+        value: f32,
+        layer: u32,
+    ) {
+        let Some(painted) = self.painted(images) else {
+            return;
+        };
+        if layer >= painted.layers {
+            return;
+        }
+
+        let size = painted.size.as_vec2();
+        let texel_size = grid_config.get_size() / size;
+        let min = (grid_config.world_to_uv(world_xy - radius) * size).floor();
+        let max = (grid_config.world_to_uv(world_xy + radius) * size).ceil();
+        let min = min.max(Vec2::ZERO).as_uvec2();
+        let max = max.min(size).as_uvec2();
+        if min.x >= max.x || min.y >= max.y {
+            return;
+        }
+
+        let byte = (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        let corner =
+            Vec2::from(grid_config.grid_center_xy) - Vec2::from(grid_config.grid_half_extents);
+        let layer_offset = (layer * painted.size.x * painted.size.y) as usize;
+        let mut painted_min = max;
+        let mut painted_max = min;
+        for y in min.y..max.y {
+            for x in min.x..max.x {
+                let texel_center = corner + (UVec2::new(x, y).as_vec2() + 0.5) * texel_size;
+                if texel_center.distance_squared(world_xy) > radius * radius {
+                    continue;
+                }
+                painted.data[layer_offset + (y * painted.size.x + x) as usize] = byte;
+                painted_min = painted_min.min(UVec2::new(x, y));
+                painted_max = painted_max.max(UVec2::new(x + 1, y + 1));
+            }
+        }
+        if painted_min.x >= painted_max.x {
+            return; //Circle fell between the texel centers
+        }
+
+        let dirty = &mut painted.dirty[layer as usize];
+        *dirty = Some(match *dirty {
+            Some((dirty_min, dirty_max)) => {
+                (dirty_min.min(painted_min), dirty_max.max(painted_max))
+            }
+            None => (painted_min, painted_max),
+        });
+    }
+
+    //Copies the texture array out of the image the first time it is painted
+    fn painted(&mut self, images: &Assets<Image>) -> Option<&mut PaintedGrowth> {
+        if self.painted.is_none() {
+            let image = images.get(self.growth_texture_array_handle.as_ref()?)?;
+            if image.texture_descriptor.format != TextureFormat::R8Unorm {
+                warn!("Can only paint R8Unorm growth textures");
+                return None;
+            }
+            let size = image.texture_descriptor.size;
+            self.painted = Some(PaintedGrowth {
+                size: UVec2::new(size.width, size.height),
+                layers: size.depth_or_array_layers,
+                data: image.data.clone(),
+                dirty: vec![None; size.depth_or_array_layers as usize],
+            });
+        }
+        self.painted.as_mut()
+    }
+
+    fn dirty_uploads(&self) -> Vec<GrowthTextureUpload> {
+        let Some(painted) = self.painted.as_ref() else {
+            return Vec::new();
+        };
+        let mut uploads = Vec::new();
+        for (layer, dirty) in painted.dirty.iter().enumerate() {
+            let Some((min, max)) = *dirty else {
+                continue;
+            };
+            let layer_offset = layer * (painted.size.x * painted.size.y) as usize;
+            let mut data = Vec::new();
+            for y in min.y..max.y {
+                let row = layer_offset + (y * painted.size.x) as usize;
+                data.extend_from_slice(&painted.data[row + min.x as usize..row + max.x as usize]);
+            }
+            uploads.push(GrowthTextureUpload {
+                layer: layer as u32,
+                origin: min,
+                size: max - min,
+                data,
+            });
         }
+        uploads
     }
 }
 
-#[derive(Clone, Default, Resource)]
+//Runs before anything paints, the previous frames paint has been extracted by now
+fn clear_painted_growth(mut growth_textures: ResMut<GrowthTextures>) {
+    if let Some(painted) = growth_textures.bypass_change_detection().painted.as_mut() {
+        painted.dirty.iter_mut().for_each(|dirty| *dirty = None);
+    }
+}
+
+#[derive(Clone, Debug, Default, Resource)]
 pub struct GridConfig {
     pub grid_center_xy: [f32; 2], //Assume axis aligned grid otherwise need to calc homogenous coordinate matrix
     pub grid_half_extents: [f32; 2],
+    pub height_map: Option<Handle<Image>>, //Lifts the grass to the terrain, spans the whole grid with the same uv as the growth textures
+    pub min_height: f32,                   //Height of a height map value of 0.0
+    pub max_height: f32,                   //Height of a height map value of 1.0
 }
 
 impl GridConfig {
@@ -212,6 +458,12 @@ impl GridConfig {
             self.grid_half_extents[1] * 2.0,
         )
     }
+
+    //Same mapping as the growth textures get in grass.wgsl, (0,0) is the corner of the grid with the lowest x and y
+    pub fn world_to_uv(&self, world_xy: Vec2) -> Vec2 {
+        (world_xy - Vec2::from(self.grid_center_xy) + Vec2::from(self.grid_half_extents))
+            / self.get_size()
+    }
 }
 
 #[derive(TypeUuid, Debug, Clone, Component, Default)]
@@ -230,6 +482,135 @@ pub struct ChunkGrass {
     pub growth_texture_id: i32,
     pub height_modifier: f32, //Height modifier of the grass, determines the width/height ratio
     pub scale: f32,           //Scale of the grass
+    pub lighting: GrassLighting,
+    pub transparent: bool, //Alpha blend the grass colors in the transparent phase instead of drawing it opaque
+}
+
+impl ChunkGrass {
+    //Straws to draw for a camera at camera_position, the part of the chunk closest to the camera decides.
+    //Straws past the count would be faded out by grass.wgsl anyway since the instance index is their fade threshold
+    pub(crate) fn lod_instance_count(
+        &self,
+        density_lod: Option<&GrassDensityLod>,
+        chunk_transform: &Mat4,
+        camera_position: Vec3,
+    ) -> u32 {
+        let Some(density_lod) = density_lod else {
+            return self.nr_instances;
+        };
+        //Assume axis aligned chunks, same as the grid
+        let corner_a = chunk_transform.transform_point3(Vec3::ZERO).truncate();
+        let corner_b = chunk_transform
+            .transform_point3(Vec2::from(self.chunk_half_extents).extend(0.0) * 2.0)
+            .truncate();
+        let camera_xy = camera_position.truncate();
+        let distance = (corner_a.min(corner_b) - camera_xy)
+            .max(camera_xy - corner_a.max(corner_b))
+            .max(Vec2::ZERO)
+            .length();
+        let visible_fraction = density_lod.density(distance) * (1.0 + GRASS_DENSITY_FADE);
+        ((self.nr_instances as f32 * visible_fraction).ceil() as u32).min(self.nr_instances)
+    }
+}
+
+//Thins the grass out with the horizontal camera distance, far chunks draw fewer straws and the ones left get wider to cover the same ground.
+//Without it a chunk draws all nr_instances straws until DistanceCulling hides it
+#[derive(Component, Clone, Copy, Debug)]
+pub struct GrassDensityLod {
+    pub start_distance: f32, //Full density closer than this
+    pub end_distance: f32,   //Density has fallen to min_density here and stays there
+    pub min_density: f32, //Fraction of the straws left far away, the straws get 1/min_density times wider
+}
+
+//Part of the density over which a straw shrinks away instead of popping, same as DENSITY_FADE in grass.wgsl
+const GRASS_DENSITY_FADE: f32 = 0.1;
+
+impl Default for GrassDensityLod {
+    fn default() -> Self {
+        Self {
+            start_distance: 25.0,
+            end_distance: 250.0,
+            min_density: 0.1,
+        }
+    }
+}
+
+impl GrassDensityLod {
+    pub fn new(start_distance: f32, end_distance: f32, min_density: f32) -> Self {
+        Self {
+            start_distance,
+            end_distance,
+            min_density,
+        }
+    }
+
+    //Fraction of the straws drawn at this distance, falls off linearly between the start and end distance
+    pub fn density(&self, distance: f32) -> f32 {
+        let t = ((distance - self.start_distance)
+            / (self.end_distance - self.start_distance).max(0.001))
+        .clamp(0.0, 1.0);
+        1.0 + (self.min_density.clamp(0.0, 1.0) - 1.0) * t
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum GrassLighting {
+    #[default]
+    Unlit, //Just the grass colors, cheapest
+    Lit, //Directional lights with shadows, ambient light, translucency and a sheen. Colors are then treated as albedo
+}
+
+//Straws bend away from entities with this, e.g animals and the player walking through the grass
+#[derive(Clone, Component, Debug)]
+pub struct GrassInteractor {
+    pub radius: f32,
+    pub strength: f32,               //1.0 pushes the straws at the center flat
+    pub trail_recovery: Option<f32>, //Seconds it takes trampled grass to stand up again, None leaves no trail
+}
+
+impl GrassInteractor {
+    pub fn new(radius: f32, strength: f32) -> Self {
+        Self {
+            radius,
+            strength,
+            trail_recovery: None,
+        }
+    }
+
+    pub fn with_trail(mut self, recovery_seconds: f32) -> Self {
+        self.trail_recovery = Some(recovery_seconds);
+        self
+    }
+}
+
+pub const MAX_GRASS_INTERACTORS: usize = 64; //Interactors and trail points past this are ignored, live interactors go first and the weakest trail points are dropped
+
+#[derive(Clone, Copy, Debug)]
+struct InteractorPoint {
+    position: Vec3,
+    radius: f32,
+    strength: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct TrailPoint {
+    point: InteractorPoint,
+    time_left: f32,
+    recovery: f32,
+}
+
+impl TrailPoint {
+    //Fades out while the grass recovers
+    fn current_strength(&self) -> f32 {
+        self.point.strength * self.time_left / self.recovery
+    }
+}
+
+#[derive(Clone, Default, Resource)]
+pub struct GrassInteractors {
+    points: Vec<InteractorPoint>, //What the grass bends away from this frame
+    trail: Vec<TrailPoint>,
+    last_trail_point: HashMap<Entity, Vec3>,
 }
 
 // ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
@@ -256,11 +637,38 @@ impl ExtractComponent for ChunkGrass {
     }
 }
 
+impl ExtractComponent for GrassDensityLod {
+    type Query = &'static GrassDensityLod;
+    type Filter = ();
+    type Out = Self;
+
+    fn extract_component(item: bevy::ecs::query::QueryItem<Self::Query>) -> Option<Self> {
+        Some(*item)
+    }
+}
+
 impl ExtractResource for GrowthTextures {
     type Source = GrowthTextures;
 
+    //Only the painted rectangles go to the render world, not the whole painted copy
     fn extract_resource(res: &Self::Source) -> Self {
-        res.clone()
+        GrowthTextures {
+            growth_texture_array_handle: res.growth_texture_array_handle.clone(),
+            painted: None,
+            uploads: res.dirty_uploads(),
+        }
+    }
+}
+
+impl ExtractResource for GrassInteractors {
+    type Source = GrassInteractors;
+
+    //The trail bookkeeping stays in the main world
+    fn extract_resource(res: &Self::Source) -> Self {
+        GrassInteractors {
+            points: res.points.clone(),
+            ..default()
+        }
     }
 }
 
@@ -292,13 +700,11 @@ pub struct ChunkGrassBindGroup {
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Zeroable, Pod, ShaderType)]
+#[derive(Clone, Copy, Zeroable, Pod)]
 pub struct GpuChunkGrass {
     //Every struct element needs to be divisable with 16 bytes or padding needs to be added. This could probably be done some other way...
     //https://www.w3.org/TR/WGSL/#alignment-and-size
     pub time: [f32; 4],
-    // pub wind_dir: [f32; 2], //Not used yet
-    // pub wind_power: f32, //Not used yet
     pub healthy_tip_color: [f32; 4],
     pub healthy_middle_color: [f32; 4],
     pub healthy_base_color: [f32; 4],
@@ -311,14 +717,17 @@ pub struct GpuChunkGrass {
     pub growth_texture_id: [i32; 4],
     pub height_modifier: [f32; 4],
     pub scale: [f32; 4],
+    pub density_lod: [f32; 4], //start distance, end distance, min density, nr_instances
 }
 
 impl ChunkGrass {
-    fn to_raw(self: &Self) -> GpuChunkGrass {
+    fn to_raw(self: &Self, density_lod: Option<&GrassDensityLod>) -> GpuChunkGrass {
+        //Full density everywhere without a lod
+        let density_lod = density_lod
+            .copied()
+            .unwrap_or(GrassDensityLod::new(0.0, 1.0, 1.0));
         GpuChunkGrass {
             time: [self.time, 0.0, 0.0, 0.0],
-            // wind_dir: [0.5, -0.5],
-            // wind_power: 1.0,
             healthy_tip_color: self.healthy_tip_color.as_linear_rgba_f32().into(),
             healthy_middle_color: self.healthy_middle_color.as_linear_rgba_f32().into(),
             healthy_base_color: self.healthy_base_color.as_linear_rgba_f32().into(),
@@ -331,20 +740,26 @@ impl ChunkGrass {
             growth_texture_id: [self.growth_texture_id, 0, 0, 0], //To lazy to understand alingment XD
             height_modifier: [self.height_modifier, 0.0, 0.0, 0.0],
             scale: [self.scale, 0.0, 0.0, 0.0],
+            density_lod: [
+                density_lod.start_distance,
+                density_lod.end_distance,
+                density_lod.min_density.clamp(0.0, 1.0),
+                self.nr_instances as f32,
+            ],
         }
     }
 }
 
 fn prepare_grass_chunk_bind_group(
     mut commands: Commands,
-    query: Query<(Entity, &ChunkGrass)>,
+    query: Query<(Entity, &ChunkGrass, Option<&GrassDensityLod>)>,
     render_device: Res<RenderDevice>,
     custom_pipeline: Res<CustomPipeline>,
 ) {
-    for (entity, grass_chunk) in &query {
+    for (entity, grass_chunk, density_lod) in &query {
         let grass_chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("Grass_chunk_buffer"),
-            contents: bytemuck::cast_slice(&[grass_chunk.to_raw()]),
+            contents: bytemuck::cast_slice(&[grass_chunk.to_raw(density_lod)]),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
 
@@ -362,6 +777,48 @@ fn prepare_grass_chunk_bind_group(
     }
 }
 
+//Writes the painted rectangles into the gpu texture, kept around until the image has been uploaded the first time
+fn upload_painted_growth(
+    render_queue: Res<RenderQueue>,
+    mut growth_textures: ResMut<GrowthTextures>,
+    images: Res<RenderAssets<Image>>,
+    mut pending: Local<Vec<GrowthTextureUpload>>,
+) {
+    pending.append(&mut growth_textures.uploads);
+    let Some(image) = growth_textures
+        .growth_texture_array_handle
+        .as_ref()
+        .and_then(|handle| images.get(handle))
+    else {
+        return;
+    };
+    for upload in pending.drain(..) {
+        render_queue.write_texture(
+            ImageCopyTexture {
+                texture: &image.texture,
+                mip_level: 0,
+                origin: Origin3d {
+                    x: upload.origin.x,
+                    y: upload.origin.y,
+                    z: upload.layer,
+                },
+                aspect: TextureAspect::All,
+            },
+            &upload.data,
+            ImageDataLayout {
+                offset: 0,
+                bytes_per_row: Some(upload.size.x),
+                rows_per_image: Some(upload.size.y),
+            },
+            Extent3d {
+                width: upload.size.x,
+                height: upload.size.y,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+}
+
 #[derive(Default, Resource)]
 pub struct GrowthTexturesBindGroup {
     pub bind_group: Option<BindGroup>,
@@ -398,23 +855,87 @@ pub fn prepare_growth_textures_bind_group(
     }
 }
 
-#[derive(Default, Resource)]
+#[derive(Resource)]
 pub struct GridConfigBindGroup {
     pub grid_config_bind_group: Option<BindGroup>,
+    waiting_for_height_map: bool, //Height map image not uploaded yet, the grass is kept flat until it is
+    interactors_buffer: Buffer, //Rewritten every frame, so it is made once and shared by every grid config bind group
+}
+
+impl FromWorld for GridConfigBindGroup {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let interactors_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("grass_interactors_buffer"),
+            contents: bytemuck::bytes_of(&GpuGrassInteractors::zeroed()),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        Self {
+            grid_config_bind_group: None,
+            waiting_for_height_map: false,
+            interactors_buffer,
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct GpuGrassInteractor {
+    pub position_radius: [f32; 4],
+    pub strength: [f32; 4],
 }
 
 #[repr(C)]
-#[derive(Clone, Copy, Zeroable, Pod, ShaderType)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct GpuGrassInteractors {
+    pub count: [u32; 4],
+    pub interactors: [GpuGrassInteractor; MAX_GRASS_INTERACTORS],
+}
+
+fn prepare_grass_interactors(
+    render_queue: Res<RenderQueue>,
+    grass_interactors: Res<GrassInteractors>,
+    grid_config_bind_group: Res<GridConfigBindGroup>,
+) {
+    if !grass_interactors.is_changed() {
+        return;
+    }
+    let mut gpu_interactors = GpuGrassInteractors::zeroed();
+    gpu_interactors.count[0] = grass_interactors.points.len() as u32;
+    for (gpu_interactor, point) in gpu_interactors
+        .interactors
+        .iter_mut()
+        .zip(&grass_interactors.points)
+    {
+        gpu_interactor.position_radius = point.position.extend(point.radius).to_array();
+        gpu_interactor.strength[0] = point.strength;
+    }
+    render_queue.write_buffer(
+        &grid_config_bind_group.interactors_buffer,
+        0,
+        bytemuck::bytes_of(&gpu_interactors),
+    );
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
 struct GpuGridConfig {
     pub grid_center_xy: [f32; 2], //Assume axis aligned grid otherwise need to calc homogenous coordinate matrix
     pub grid_half_extents: [f32; 2],
+    pub height_range: [f32; 4], //min height, max height, 1.0 if a height map is bound
 }
 
 impl GridConfig {
-    fn to_raw(self: &Self) -> GpuGridConfig {
+    fn to_raw(self: &Self, has_height_map: bool) -> GpuGridConfig {
         GpuGridConfig {
             grid_center_xy: self.grid_center_xy.clone(),
             grid_half_extents: self.grid_half_extents.clone(),
+            height_range: [
+                self.min_height,
+                self.max_height,
+                if has_height_map { 1.0 } else { 0.0 },
+                0.0,
+            ],
         }
     }
 }
@@ -424,21 +945,50 @@ fn prepare_grid_config_bind_group(
     mut grid_config_bind_group_res: ResMut<GridConfigBindGroup>,
     grid_config: Res<GridConfig>,
     custom_pipeline: Res<CustomPipeline>,
+    images: Res<RenderAssets<Image>>,
+    fallback_image: Res<FallbackImage>,
+    wind_buffer: Res<WindBuffer>,
 ) {
-    if grid_config.is_changed() {
+    if grid_config.is_changed() || grid_config_bind_group_res.waiting_for_height_map {
+        let height_map = grid_config
+            .height_map
+            .as_ref()
+            .and_then(|handle| images.get(handle));
+        grid_config_bind_group_res.waiting_for_height_map =
+            grid_config.height_map.is_some() && height_map.is_none();
+
         let grid_config_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("grid_config_buffer"),
-            contents: bytemuck::cast_slice(&[grid_config.to_raw()]),
+            contents: bytemuck::cast_slice(&[grid_config.to_raw(height_map.is_some())]),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
 
+        //Shader skips the height map when none is bound so any texture works as a stand in
+        let height_map_view = &height_map.unwrap_or(&fallback_image.d2).texture_view;
+
         let grid_config_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
             label: Some("grid_config_bindgroup"),
             layout: &custom_pipeline.grid_config_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: grid_config_buffer.as_entire_binding(),
-            }],
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: grid_config_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(height_map_view),
+                },
+                BindGroupEntry {
+                    binding: 2,
+                    resource: grid_config_bind_group_res
+                        .interactors_buffer
+                        .as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 3,
+                    resource: wind_buffer.as_entire_binding(),
+                },
+            ],
         });
 
         grid_config_bind_group_res.as_mut().grid_config_bind_group = Some(grid_config_bind_group);
@@ -459,43 +1009,96 @@ fn prepare_grid_config_bind_group(
 // █░░░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█
 // ██████████████████████████████████████████████████████████████████████████████
 
-#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn queue_custom_pipeline(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<CustomPipeline>,
     msaa: Res<Msaa>,
     mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
     pipeline_cache: Res<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
-    material_meshes: Query<(Entity, &MeshUniform, &Handle<Mesh>), With<ChunkGrass>>,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
+    material_meshes: Query<(Entity, &MeshUniform, &Handle<Mesh>, &ChunkGrass)>,
+    mut views: Query<(
+        &ExtractedView,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        Option<&EnvironmentMapLight>,
+        Option<&ScreenSpaceAmbientOcclusionSettings>,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
     growth_textures: Res<GrowthTextures>,
+    images: Res<RenderAssets<Image>>,
 ) {
-    let draw_custom = transparent_3d_draw_functions
+    let draw_custom_opaque = opaque_3d_draw_functions
+        .read()
+        .get_id::<DrawCustom>()
+        .unwrap();
+    let draw_custom_transparent = transparent_3d_draw_functions
         .read()
         .get_id::<DrawCustom>()
         .unwrap();
 
     let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
 
-    for (view, mut transparent_phase) in &mut views {
+    for (
+        view,
+        tonemapping,
+        dither,
+        environment_map,
+        ssao,
+        mut opaque_phase,
+        mut transparent_phase,
+    ) in &mut views
+    {
         let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
+        //Lit grass needs the same tonemapping as the pbr meshes around it
+        let lit_view_key = pbr_view_key(
+            &msaa,
+            view,
+            tonemapping,
+            dither,
+            environment_map,
+            ssao,
+            &images,
+        );
         let rangefinder = view.rangefinder3d();
-        for (entity, mesh_uniform, mesh_handle) in &material_meshes {
+        for (entity, mesh_uniform, mesh_handle, grass_chunk) in &material_meshes {
             if let Some(mesh) = meshes.get(mesh_handle) {
                 //Only render stuff if there is a texture handle
                 if growth_textures.growth_texture_array_handle.is_some() {
-                    let key = view_key
-                        | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+                    let mut mesh_key =
+                        match grass_chunk.lighting {
+                            GrassLighting::Unlit => view_key,
+                            GrassLighting::Lit => lit_view_key,
+                        } | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
+                    if grass_chunk.transparent {
+                        mesh_key |= MeshPipelineKey::BLEND_ALPHA;
+                    }
+                    let key = GrassPipelineKey {
+                        mesh_key,
+                        lighting: grass_chunk.lighting,
+                    };
                     let pipeline = pipelines
                         .specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout)
                         .unwrap();
-                    transparent_phase.add(Transparent3d {
-                        entity,
-                        pipeline,
-                        draw_function: draw_custom,
-                        distance: rangefinder.distance(&mesh_uniform.transform),
-                    });
+                    let distance = rangefinder.distance(&mesh_uniform.transform);
+                    if grass_chunk.transparent {
+                        transparent_phase.add(Transparent3d {
+                            entity,
+                            pipeline,
+                            draw_function: draw_custom_transparent,
+                            distance,
+                        });
+                    } else {
+                        opaque_phase.add(Opaque3d {
+                            entity,
+                            pipeline,
+                            draw_function: draw_custom_opaque,
+                            distance,
+                        });
+                    }
                 }
             }
         }
@@ -578,17 +1181,50 @@ impl FromWorld for CustomPipeline {
         //NEW grid config STUFF
         let grid_config_bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX_FRAGMENT,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false, //size will not change
-                        // min_binding_size: Some(GpuGrassMaterial::min_size()),
-                        min_binding_size: None,
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::VERTEX_FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false, //size will not change
+                            // min_binding_size: Some(GpuGrassMaterial::min_size()),
+                            min_binding_size: None,
+                        },
+                        count: None,
                     },
-                    count: None,
-                }],
+                    //Height map, loaded texel by texel and filtered in the shader so float formats without filtering work too
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Texture {
+                            multisampled: false,
+                            view_dimension: TextureViewDimension::D2,
+                            sample_type: TextureSampleType::Float { filterable: false },
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 2,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 3,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
+                        count: None,
+                    },
+                ],
                 label: Some("grid_config_bind_group_layout"),
             });
         //grid END
@@ -609,19 +1245,41 @@ impl FromWorld for CustomPipeline {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GrassPipelineKey {
+    pub mesh_key: MeshPipelineKey,
+    pub lighting: GrassLighting,
+}
+
 impl SpecializedMeshPipeline for CustomPipeline {
-    type Key = MeshPipelineKey;
+    type Key = GrassPipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
+        let mut descriptor = self.mesh_pipeline.specialize(key.mesh_key, layout)?;
 
         descriptor.primitive.cull_mode = None; //For grass
+
+        //Opaque grass writes depth so everything behind it gets rejected early, transparent grass only tests against it
+        if let Some(depth_stencil) = descriptor.depth_stencil.as_mut() {
+            depth_stencil.depth_write_enabled =
+                !key.mesh_key.contains(MeshPipelineKey::BLEND_ALPHA);
+            depth_stencil.depth_compare = CompareFunction::GreaterEqual;
+        }
         descriptor.vertex.shader = self.shader.clone();
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+        if key.lighting == GrassLighting::Lit {
+            descriptor.vertex.shader_defs.push("GRASS_LIT".into());
+            descriptor
+                .fragment
+                .as_mut()
+                .unwrap()
+                .shader_defs
+                .push("GRASS_LIT".into());
+        }
         descriptor.layout = vec![
             self.mesh_pipeline.view_layout_multisampled.clone(),
             self.mesh_pipeline.mesh_layouts.model_only.clone(),
@@ -733,17 +1391,21 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
     type Param = (
         SRes<RenderAssets<Mesh>>,
         SQuery<Read<Handle<Mesh>>>,
-        SQuery<Read<ChunkGrass>>, //TODO: Should this be an ItemWorldQuery? instead checkout https://github.com/bevyengine/bevy/blob/c2b85f9b52a3dc4c0d573e33107ee3ac9fd8f4e5/examples/shader/shader_instancing.rs#L242
+        Option<SRes<OccludedGrassBuffers>>,
     );
-    type ItemWorldQuery = ();
-    type ViewWorldQuery = ();
+    type ItemWorldQuery = (
+        Read<ChunkGrass>,
+        Read<MeshUniform>,
+        Option<Read<GrassDensityLod>>,
+    );
+    type ViewWorldQuery = (Entity, Read<ExtractedView>);
 
     #[inline]
     fn render<'w>(
         item: &P,
-        _view: ROQueryItem<'w, Self::ViewWorldQuery>,
-        _: ROQueryItem<'w, Self::ItemWorldQuery>,
-        (meshes, mesh_query, grass_chunk): SystemParamItem<'w, '_, Self::Param>,
+        (view, extracted_view): ROQueryItem<'w, Self::ViewWorldQuery>,
+        (grass_chunk, mesh_uniform, density_lod): ROQueryItem<'w, Self::ItemWorldQuery>,
+        (meshes, mesh_query, occluded_grass_buffers): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         let mesh_handle = mesh_query.get(item.entity()).unwrap();
@@ -753,6 +1415,11 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
             None => return RenderCommandResult::Failure,
         };
 
+        //Occluded chunks get their instance count set to 0 by the occlusion culling
+        let occluded_grass = occluded_grass_buffers
+            .and_then(|occluded| occluded.into_inner().0.get(&(view, item.entity())))
+            .filter(|occluded| occluded.ready);
+
         pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
 
         match &gpu_mesh.buffer_info {
@@ -762,11 +1429,16 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
                 count,
             } => {
                 pass.set_index_buffer(buffer.slice(..), 0, *index_format);
-                pass.draw_indexed(
-                    0..*count,
-                    0,
-                    0..grass_chunk.get(item.entity()).unwrap().nr_instances as u32,
+                if let Some(occluded_grass) = occluded_grass {
+                    pass.draw_indexed_indirect(&occluded_grass.indirect_buffer, 0);
+                    return RenderCommandResult::Success;
+                }
+                let nr_instances = grass_chunk.lod_instance_count(
+                    density_lod,
+                    &mesh_uniform.transform,
+                    extracted_view.transform.translation(),
                 );
+                pass.draw_indexed(0..*count, 0, 0..nr_instances);
             }
             _ => {
                 panic!("Non indexed not supported")
@@ -1,13 +1,24 @@
 use bevy::{
-    core_pipeline::core_3d::Transparent3d,
+    asset::HandleId,
+    core_pipeline::{
+        core_3d::{AlphaMask3d, Opaque3d, Transparent3d},
+        tonemapping::{DebandDither, Tonemapping},
+    },
     ecs::{
         query::ROQueryItem,
         system::{lifetimeless::*, SystemParamItem},
     },
-    pbr::{MeshPipeline, MeshPipelineKey, MeshUniform, SetMeshBindGroup, SetMeshViewBindGroup},
+    pbr::{
+        CascadesVisibleEntities, CubemapVisibleEntities, EnvironmentMapLight,
+        ExtractedDirectionalLight, ExtractedPointLight, LightEntity, MaterialPipeline,
+        MaterialPipelineKey, MeshPipelineKey, MeshUniform, NotShadowCaster, PreparedMaterial,
+        PrepassPipeline, RenderMaterials, ScreenSpaceAmbientOcclusionSettings, SetMeshBindGroup,
+        SetMeshViewBindGroup, SetPrepassViewBindGroup, Shadow, StandardMaterialKey,
+        ViewLightEntities, SHADOW_FORMAT,
+    },
     prelude::*,
     render::{
-        mesh::{GpuBufferInfo, MeshVertexBufferLayout},
+        mesh::{GpuBufferInfo, GpuMesh, Indices, MeshVertexBufferLayout},
         primitives::Aabb,
         render_asset::RenderAssets,
         render_phase::{
@@ -15,15 +26,23 @@ use bevy::{
             RenderPhase, SetItemPipeline, TrackedRenderPass,
         },
         render_resource::*,
-        renderer::RenderDevice,
-        view::{ComputedVisibility, ExtractedView, Msaa},
+        renderer::{RenderDevice, RenderQueue},
+        view::{ComputedVisibility, ExtractedView, Msaa, VisibleEntities},
         Extract, Render, RenderApp, RenderSet,
     },
+    utils::{HashMap, HashSet},
 };
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
 
-use super::{Chunk, DistanceCulling};
+use super::{
+    gpu_culling::{CulledInstanceBuffers, InstanceCullingPipeline},
+    pbr_view_key,
+    scatter::{DensityMap, HeightSource, Scatter},
+    wind::{WindBuffer, WindPlugin},
+    Chunk, DistanceCulling,
+};
 
 //Bundle
 #[derive(Bundle, Debug, Default)]
@@ -58,41 +77,466 @@ fn chunk_distance_culling(
     }
 }
 
+//Mesh LODs for a chunk, insert next to the ChunkInstancingBundle.
+//The first level whose distance is larger than the camera distance to the chunk center is drawn,
+//beyond the last level the last mesh is kept and DistanceCulling takes over.
+//With an impostor the chunk switches to camera facing quads from the impostor distance and on.
+#[derive(Component, Clone, Debug, Default)]
+pub struct ChunkLod {
+    pub levels: Vec<LodLevel>, //Sorted by distance, closest first
+    pub impostor: Option<Impostor>,
+    impostor_active: bool, //Set by chunk_lod_selection
+}
+
+#[derive(Clone, Debug)]
+pub struct LodLevel {
+    pub mesh: Handle<Mesh>,
+    pub distance: f32, //Used while the camera is closer than this
+}
+
+//Billboard far LOD, drawn with the same instance positions and scales as the full mesh
+#[derive(Clone, Debug)]
+pub struct Impostor {
+    pub mesh: Handle<Mesh>,                 //Quad from get_impostor_mesh
+    pub material: Handle<StandardMaterial>, //Impostor texture as base_color_texture, usually with AlphaMode::Mask
+    pub distance: f32,                      //Used from this camera distance and further away
+    pub atlas_columns: u32, //Views of the tree side by side in the texture, 1 for a single view. See billboard_uv in the shader
+}
+
+//What ChunkLod::select picked for a camera distance
+#[derive(Clone, Copy, Debug)]
+pub enum LodSelection<'a> {
+    Level(&'a LodLevel),
+    Impostor(&'a Impostor),
+}
+
+impl<'a> LodSelection<'a> {
+    pub fn mesh(&self) -> &'a Handle<Mesh> {
+        match self {
+            LodSelection::Level(level) => &level.mesh,
+            LodSelection::Impostor(impostor) => &impostor.mesh,
+        }
+    }
+}
+
+//Quad for Impostor::mesh, standing on the origin in the xz plane. Width and height in the same units as the full mesh
+pub fn get_impostor_mesh(width: f32, height: f32) -> Mesh {
+    let half_width = width / 2.0;
+    let positions = vec![
+        [-half_width, 0.0, 0.0],
+        [half_width, 0.0, 0.0],
+        [half_width, 0.0, height],
+        [-half_width, 0.0, height],
+    ];
+    let normals = vec![[0.0, -1.0, 0.0]; 4];
+    let uvs = vec![[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+    let indices = vec![0, 1, 2, 0, 2, 3];
+
+    let mut mesh = Mesh::new(PrimitiveTopology::TriangleList);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_POSITION, positions);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_NORMAL, normals);
+    mesh.insert_attribute(Mesh::ATTRIBUTE_UV_0, uvs);
+    mesh.set_indices(Some(Indices::U32(indices)));
+    mesh
+}
+
+impl ChunkLod {
+    pub fn new(mut levels: Vec<LodLevel>) -> Self {
+        levels.sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        Self {
+            levels,
+            ..default()
+        }
+    }
+
+    pub fn with_level(mut self, mesh: Handle<Mesh>, distance: f32) -> Self {
+        self.levels.push(LodLevel { mesh, distance });
+        self.levels
+            .sort_by(|a, b| a.distance.total_cmp(&b.distance));
+        self
+    }
+
+    pub fn with_impostor(mut self, impostor: Impostor) -> Self {
+        self.impostor = Some(impostor);
+        self
+    }
+
+    pub fn select(&self, distance: f32) -> Option<LodSelection<'_>> {
+        if let Some(impostor) = &self.impostor {
+            if distance >= impostor.distance {
+                return Some(LodSelection::Impostor(impostor));
+            }
+        }
+        self.levels
+            .iter()
+            .find(|level| distance < level.distance)
+            .or(self.levels.last())
+            .map(LodSelection::Level)
+    }
+
+    pub fn active_impostor(&self) -> Option<&Impostor> {
+        self.impostor.as_ref().filter(|_| self.impostor_active)
+    }
+}
+
+//Swaps the chunk mesh to the active LOD, the instance buffer stays the same so only the mesh changes
+fn chunk_lod_selection(
+    mut query: Query<(&Transform, &Aabb, &mut ChunkLod, &mut Handle<Mesh>)>,
+    query_camera: Query<&Transform, With<Camera>>,
+) {
+    if let Ok(camera_pos) = query_camera.get_single() {
+        for (transform, aabb, mut chunk_lod, mut mesh_handle) in query.iter_mut() {
+            //Chunk transforms sit in the corner of the chunk so use the aabb center instead
+            let chunk_center = transform.transform_point(aabb.center.into());
+            let distance = camera_pos.translation.distance(chunk_center);
+            let selection = chunk_lod.select(distance);
+            let impostor_active = matches!(selection, Some(LodSelection::Impostor(_)));
+            if let Some(selection) = selection {
+                if *mesh_handle != *selection.mesh() {
+                    *mesh_handle = selection.mesh().clone();
+                }
+            }
+            if chunk_lod.impostor_active != impostor_active {
+                chunk_lod.impostor_active = impostor_active;
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct Instance {
-    pub pos_xyz: [f32; 4],
+    pub pos_xyz: [f32; 4], //[x,y,z, scale]
+    pub rotation: Quat,    //Only used with InstanceLayout::Full
+    pub scale: Vec3, //Only used with InstanceLayout::Full, multiplied with the scale in pos_xyz
+    pub tint: Color, //Only used if ChunkInstancing::tinted, multiplied with the base color
 }
 
-#[derive(Component, Clone, Debug, Default)]
+impl Default for Instance {
+    fn default() -> Self {
+        Self {
+            pos_xyz: [0.0, 0.0, 0.0, 1.0],
+            rotation: Quat::IDENTITY,
+            scale: Vec3::ONE,
+            tint: Color::WHITE,
+        }
+    }
+}
+
+impl Instance {
+    pub fn new(position: Vec3, scale: f32) -> Self {
+        Self {
+            pos_xyz: [position.x, position.y, position.z, scale],
+            ..default()
+        }
+    }
+
+    //Needs InstanceLayout::Full in order to use the rotation and non uniform scale
+    pub fn from_transform(transform: &Transform) -> Self {
+        Self {
+            pos_xyz: [
+                transform.translation.x,
+                transform.translation.y,
+                transform.translation.z,
+                1.0,
+            ],
+            rotation: transform.rotation,
+            scale: transform.scale,
+            ..default()
+        }
+    }
+}
+
+//Determines what per instance data is sent to the gpu
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum InstanceLayout {
+    //[x,y,z, scale], rotation around z and a small scale variation is randomized on the shader
+    #[default]
+    Compact,
+    //[x,y,z, scale] + rotation quaternion + xyz scale, for placing instances deliberately
+    Full,
+}
+
+//Where the wind sway of the vertices is weighted from
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum SwayWeights {
+    //Bends more the higher above the model origin the vertex is, leaves flutter the same way
+    #[default]
+    Height,
+    //Red is the trunk bend and green the leaf flutter, painted in the modelling tool. Falls back to Height without vertex colors
+    VertexColors,
+}
+
+//Everything needed to know how the instance buffer is packed
+#[derive(Component, Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub struct InstanceFormat {
+    pub layout: InstanceLayout,
+    pub tinted: bool,
+}
+
+impl InstanceFormat {
+    //Number of floats per instance
+    pub(crate) fn stride(&self) -> usize {
+        let transform_stride = match self.layout {
+            InstanceLayout::Compact => 4,
+            InstanceLayout::Full => 12,
+        };
+        transform_stride + if self.tinted { 4 } else { 0 }
+    }
+
+    fn vertex_attributes(&self) -> Vec<VertexAttribute> {
+        // shader locations 0-7 are reserved for the mesh attributes (Position, Normal, UV, Tangent, Color, Joints)
+        let mut shader_locations = vec![8]; // [x,y,z, scale]
+        if self.layout == InstanceLayout::Full {
+            shader_locations.extend([9, 10]); // rotation, scale
+        }
+        if self.tinted {
+            shader_locations.push(11); // tint
+        }
+        shader_locations
+            .into_iter()
+            .enumerate()
+            .map(|(i, shader_location)| VertexAttribute {
+                format: VertexFormat::Float32x4,
+                offset: (i * 4 * std::mem::size_of::<f32>()) as u64,
+                shader_location,
+            })
+            .collect()
+    }
+}
+
+#[derive(Component, Clone, Debug)]
 pub struct ChunkInstancing {
-    pub instances: Vec<Instance>, //[x,y,z, scale] Lower performance if using full Transforms
-    pub base_color_texture: Handle<Image>,
+    pub instances: Vec<Instance>,
+    pub material: Handle<StandardMaterial>,
     pub model_transform: Transform,
+    pub instance_layout: InstanceLayout, //Lower performance if using full Transforms
+    pub tinted: bool,                    //Send Instance::tint to the gpu
+    pub alpha_mask: Option<f32>, //Alpha cutoff, overrides the alpha mode of the material. Use for leaf cards etc
+    pub alpha_to_coverage: bool, //Smoother alpha mask edges when msaa is on
+    pub seed: Option<u64>, //Seed of the random placement and tint, None gives a different result every time
+    pub stiffness: f32, //How much the wind moves the instances, 0.0 sways freely and 1.0 doesn't move at all (rocks, the default)
+    pub sway_weights: SwayWeights,
 }
 
+impl Default for ChunkInstancing {
+    fn default() -> Self {
+        Self {
+            instances: Vec::new(),
+            material: Handle::default(),
+            model_transform: Transform::default(),
+            instance_layout: InstanceLayout::Compact,
+            tinted: false,
+            alpha_mask: None,
+            alpha_to_coverage: false,
+            seed: None,
+            stiffness: 1.0, //Opt in to wind with with_stiffness
+            sway_weights: SwayWeights::Height,
+        }
+    }
+}
+
+//Every random thing done to the instances gets its own stream, so changing one doesn't move the others around
+const PLACEMENT_STREAM: u64 = 0;
+const TINT_STREAM: u64 = 1;
+const DENSITY_STREAM: u64 = 2;
+const ALIGNMENT_STREAM: u64 = 3;
+const SCALE_STREAM: u64 = 4; //Scales of scattered instances, the placement stream is the one the scatter points come from
+
 impl ChunkInstancing {
+    //Random placement that differs on every run, use new_seeded to get the same instances every time
     pub fn new(
         nr_instances: u32,
-        base_color_texture: Handle<Image>,
+        material: Handle<StandardMaterial>,
+        model_transform: Transform,
+        chunk_size: f32,
+    ) -> Self {
+        Self::with_seed(nr_instances, material, model_transform, chunk_size, None)
+    }
+
+    //Same world seed, chunk and layer always gives identical instances, see Chunk::seed
+    pub fn new_seeded(
+        nr_instances: u32,
+        material: Handle<StandardMaterial>,
+        model_transform: Transform,
+        chunk_size: f32,
+        world_seed: u64,
+        chunk: &Chunk,
+        layer: u32,
+    ) -> Self {
+        Self::with_seed(
+            nr_instances,
+            material,
+            model_transform,
+            chunk_size,
+            Some(chunk.seed(world_seed, layer)),
+        )
+    }
+
+    //Poisson-disk placement with the spacing and exclusions of the Scatter, deterministic like new_seeded
+    pub fn new_scattered(
+        scatter: &Scatter,
+        material: Handle<StandardMaterial>,
+        model_transform: Transform,
+        chunk_size: f32,
+        world_seed: u64,
+        chunk: &Chunk,
+    ) -> Self {
+        let mut chunk_instancing = Self::with_seed(
+            0,
+            material,
+            model_transform,
+            chunk_size,
+            Some(chunk.seed(world_seed, scatter.layer)),
+        );
+        let mut rng = chunk_instancing.rng(SCALE_STREAM);
+        chunk_instancing.instances = scatter
+            .points(world_seed, chunk, chunk_size)
+            .into_iter()
+            .map(|position| Instance::new(position.extend(0.0), rng.gen::<f32>() * 0.5 + 0.5))
+            .collect();
+        chunk_instancing
+    }
+
+    fn with_seed(
+        nr_instances: u32,
+        material: Handle<StandardMaterial>,
         model_transform: Transform,
         chunk_size: f32,
+        seed: Option<u64>,
     ) -> Self {
-        let mut rng = rand::thread_rng();
-        let mut instances = Vec::new();
+        let mut chunk_instancing = Self {
+            instances: Vec::with_capacity(nr_instances as usize),
+            material,
+            model_transform,
+            seed,
+            ..default()
+        };
+
+        let mut rng = chunk_instancing.rng(PLACEMENT_STREAM);
         for _ in 0..nr_instances {
             let x = rng.gen::<f32>() * chunk_size;
             let y = rng.gen::<f32>() * chunk_size;
             let scale = rng.gen::<f32>() * 0.5 + 0.5;
 
-            instances.push(Instance {
-                pos_xyz: [x, y, 0.0, scale],
-            });
+            chunk_instancing
+                .instances
+                .push(Instance::new(Vec3::new(x, y, 0.0), scale));
         }
+        chunk_instancing
+    }
 
-        Self {
-            instances,
-            base_color_texture,
-            model_transform,
+    //ChaCha gives the same numbers on every platform and rand version, which thread_rng doesn't promise
+    fn rng(&self, stream: u64) -> ChaCha8Rng {
+        let mut rng = match self.seed {
+            Some(seed) => ChaCha8Rng::seed_from_u64(seed),
+            None => ChaCha8Rng::from_entropy(),
+        };
+        rng.set_stream(stream);
+        rng
+    }
+
+    pub fn with_instance_layout(mut self, instance_layout: InstanceLayout) -> Self {
+        self.instance_layout = instance_layout;
+        self
+    }
+
+    //Keeps each instance with the chance the density map has at its position, so the instance count given to the
+    //constructor is the count at full density. Chunk origin is the world position the instances are placed from
+    pub fn with_density_map(mut self, density_map: &DensityMap, chunk_origin: Vec2) -> Self {
+        let mut rng = self.rng(DENSITY_STREAM);
+        self.instances.retain(|instance| {
+            let world_xy = chunk_origin + Vec2::new(instance.pos_xyz[0], instance.pos_xyz[1]);
+            rng.gen::<f32>() < density_map.density(world_xy)
+        });
+        self
+    }
+
+    //Puts every instance on the terrain, the chunk transform is then expected to stay at z 0 and its Aabb has to
+    //cover the heights. With a max slope (radians) instances on steeper ground than that are removed
+    pub fn with_height(
+        mut self,
+        height_source: &HeightSource,
+        chunk_origin: Vec2,
+        max_slope: Option<f32>,
+    ) -> Self {
+        self.instances.retain_mut(|instance| {
+            let world_xy = chunk_origin + Vec2::new(instance.pos_xyz[0], instance.pos_xyz[1]);
+            if max_slope.is_some_and(|max_slope| height_source.slope(world_xy) > max_slope) {
+                return false;
+            }
+            instance.pos_xyz[2] = height_source.height(world_xy);
+            true
+        });
+        self
+    }
+
+    //Tilts the instances towards the terrain normal, 0 keeps them upright and 1 lines them up with the ground.
+    //Applied on top of the model_transform. Needs a rotation per instance so the layout is switched to InstanceLayout::Full,
+    //the random rotation around z and scale variation the shader does for InstanceLayout::Compact are baked in then
+    pub fn with_terrain_alignment(
+        mut self,
+        height_source: &HeightSource,
+        chunk_origin: Vec2,
+        alignment: f32,
+    ) -> Self {
+        if alignment <= 0.0 {
+            return self;
+        }
+        let mut rng = self.rng(ALIGNMENT_STREAM);
+        let was_compact = self.instance_layout == InstanceLayout::Compact;
+        for instance in self.instances.iter_mut() {
+            if was_compact {
+                instance.rotation = Quat::from_rotation_z(rng.gen::<f32>() * std::f32::consts::TAU);
+                instance.scale = Vec3::splat(rng.gen::<f32>() * 0.2 + 0.9);
+            }
+            let world_xy = chunk_origin + Vec2::new(instance.pos_xyz[0], instance.pos_xyz[1]);
+            let normal = height_source.normal(world_xy);
+            let up = Vec3::Z.lerp(normal, alignment.min(1.0)).normalize();
+            instance.rotation = Quat::from_rotation_arc(Vec3::Z, up) * instance.rotation;
+        }
+        self.instance_layout = InstanceLayout::Full;
+        self
+    }
+
+    //Gives every instance a random tint, each color channel is randomized separately between min and max
+    pub fn with_random_tint(mut self, min: Color, max: Color) -> Self {
+        let mut rng = self.rng(TINT_STREAM);
+        let min = Vec4::from(min.as_rgba_f32());
+        let max = Vec4::from(max.as_rgba_f32());
+        for instance in self.instances.iter_mut() {
+            let t = Vec4::new(rng.gen(), rng.gen(), rng.gen(), rng.gen());
+            instance.tint = Color::from(min + (max - min) * t);
+        }
+        self.tinted = true;
+        self
+    }
+
+    //Renders as alpha mask (AlphaMask3d phase with depth writes), fragments with alpha below the cutoff are discarded
+    pub fn with_alpha_mask(mut self, alpha_cutoff: f32) -> Self {
+        self.alpha_mask = Some(alpha_cutoff);
+        self
+    }
+
+    //Only has an effect for alpha masked materials and with msaa on, otherwise the normal cutoff is used
+    pub fn with_alpha_to_coverage(mut self) -> Self {
+        self.alpha_to_coverage = true;
+        self
+    }
+
+    pub fn with_stiffness(mut self, stiffness: f32) -> Self {
+        self.stiffness = stiffness;
+        self
+    }
+
+    pub fn with_sway_weights(mut self, sway_weights: SwayWeights) -> Self {
+        self.sway_weights = sway_weights;
+        self
+    }
+
+    fn instance_format(&self) -> InstanceFormat {
+        InstanceFormat {
+            layout: self.instance_layout,
+            tinted: self.tinted,
         }
     }
 }
@@ -102,6 +546,10 @@ pub struct ChunkInstancingPlugin;
 impl Plugin for ChunkInstancingPlugin {
     fn build(&self, app: &mut App) {
         app.add_systems(Update, chunk_distance_culling);
+        app.add_systems(Update, chunk_lod_selection);
+        if !app.is_plugin_added::<WindPlugin>() {
+            app.add_plugins(WindPlugin);
+        }
 
         let render_app = match app.get_sub_app_mut(RenderApp) {
             Ok(render_app) => render_app,
@@ -109,8 +557,18 @@ impl Plugin for ChunkInstancingPlugin {
         };
 
         render_app
+            .add_render_command::<Opaque3d, DrawCustom>()
+            .add_render_command::<AlphaMask3d, DrawCustom>()
             .add_render_command::<Transparent3d, DrawCustom>()
+            .add_render_command::<Opaque3d, DrawCustomBatch>()
+            .add_render_command::<AlphaMask3d, DrawCustomBatch>()
+            .add_render_command::<Transparent3d, DrawCustomBatch>()
+            .add_render_command::<Shadow, DrawCustomShadow>()
             .init_resource::<SpecializedMeshPipelines<CustomPipeline>>()
+            .init_resource::<SpecializedMeshPipelines<CustomShadowPipeline>>()
+            .init_resource::<ExtractedChunkInstances>()
+            .init_resource::<ChunkInstancingInstanceBuffers>()
+            .init_resource::<ChunkBatches>()
             .add_systems(ExtractSchedule, extract_chunk_instancings)
             .add_systems(
                 Render,
@@ -118,13 +576,16 @@ impl Plugin for ChunkInstancingPlugin {
             )
             .add_systems(
                 Render,
-                prepare_textures_bind_group.in_set(RenderSet::Prepare),
+                prepare_chunk_batches
+                    .in_set(RenderSet::Prepare)
+                    .after(prepare_chunk_instancing_instance_buffers),
             )
             .add_systems(
                 Render,
                 prepare_grass_chunk_bind_group.in_set(RenderSet::Prepare),
             )
-            .add_systems(Render, queue_custom.in_set(RenderSet::Queue));
+            .add_systems(Render, queue_custom.in_set(RenderSet::Queue))
+            .add_systems(Render, queue_custom_shadows.in_set(RenderSet::Queue));
     }
 
     fn finish(&self, app: &mut App) {
@@ -134,6 +595,7 @@ impl Plugin for ChunkInstancingPlugin {
         };
 
         render_app.init_resource::<CustomPipeline>();
+        render_app.init_resource::<CustomShadowPipeline>();
     }
 }
 
@@ -152,21 +614,50 @@ impl Plugin for ChunkInstancingPlugin {
 // ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
 
 //Make custom extract func in order to not clone instance data twice when using convinient abstract types for world side components
+//Instance data is only extracted when the ChunkInstancing component has changed, the render world keeps the gpu buffers between frames
+#[allow(clippy::type_complexity)]
 fn extract_chunk_instancings(
     mut commands: Commands,
     mut previous_len: Local<usize>,
-    mut query: Extract<Query<(Entity, &ComputedVisibility, &ChunkInstancing)>>,
+    mut extracted_instances: ResMut<ExtractedChunkInstances>,
+    mut query: Extract<
+        Query<(
+            Entity,
+            &ComputedVisibility,
+            Ref<ChunkInstancing>,
+            Option<&ChunkLod>,
+        )>,
+    >,
+    mut removed: Extract<RemovedComponents<ChunkInstancing>>,
 ) {
+    extracted_instances.removed.extend(removed.iter());
+
     if !query.is_empty() {
         let mut values = Vec::with_capacity(*previous_len);
-        for (entity, computed_visibility, query_item) in query.iter_mut() {
+        for (entity, computed_visibility, query_item, chunk_lod) in query.iter_mut() {
+            //Upload changes even for hidden chunks so they are up to date when they become visible
+            if query_item.is_changed() {
+                extracted_instances
+                    .changed
+                    .push((entity, query_item.to_raw_instances()));
+            }
             if computed_visibility.is_visible() {
+                let impostor = chunk_lod.and_then(|chunk_lod| chunk_lod.active_impostor());
                 values.push((
                     entity,
                     (
-                        query_item.to_raw_instances(),
-                        query_item.to_raw_chunk_bind_group(),
-                        query_item.base_color_texture.clone(),
+                        query_item.to_raw_chunk_bind_group(impostor),
+                        ChunkInstancingMaterial {
+                            handle: impostor.map_or(query_item.material.clone(), |impostor| {
+                                impostor.material.clone()
+                            }),
+                            alpha_mask: query_item.alpha_mask.is_some(),
+                            alpha_to_coverage: query_item.alpha_to_coverage,
+                            billboard: impostor.is_some(),
+                            sway_vertex_colors: query_item.sway_weights
+                                == SwayWeights::VertexColors,
+                        },
+                        query_item.instance_format(),
                     ),
                 ));
             }
@@ -176,34 +667,94 @@ fn extract_chunk_instancings(
     }
 }
 
-#[repr(C)]
-#[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
-struct GpuInstance {
-    pub pos_xyz: [f32; 4],
+//Instance data packed according to the InstanceFormat
+pub struct GpuInstances {
+    data: Vec<f32>,
+    nr_instances: usize,
 }
 
-#[derive(Component, Clone)]
-pub struct GpuInstances(Vec<GpuInstance>);
+//Instance data of chunks that changed since last frame and chunks that have been removed
+#[derive(Resource, Default)]
+pub struct ExtractedChunkInstances {
+    changed: Vec<(Entity, GpuInstances)>,
+    removed: Vec<Entity>,
+}
 
 #[derive(Component, Clone)]
-struct GpuChunkBindGroupData {
+pub struct GpuChunkBindGroupData {
     model_transform: [[f32; 4]; 4],
+    normal_transform: [[f32; 4]; 3], //Inverse transpose of the model transform, mat3x3 columns are padded to 16 bytes
+    params: [f32; 4],                //[alpha cutoff, impostor atlas columns, stiffness, padding]
+}
+
+impl GpuChunkBindGroupData {
+    //Layout of PlantChunk in the shader
+    fn to_raw_uniform(&self) -> [f32; 32] {
+        let mut raw = [0.0; 32];
+        raw[..16].copy_from_slice(bytemuck::cast::<_, [f32; 16]>(self.model_transform).as_slice());
+        raw[16..28]
+            .copy_from_slice(bytemuck::cast::<_, [f32; 12]>(self.normal_transform).as_slice());
+        raw[28..].copy_from_slice(&self.params);
+        raw
+    }
+}
+
+//Alpha mode the chunk is drawn with, ChunkInstancing::alpha_mask overrides the one of the material
+fn chunk_alpha_mode(
+    material_handle: &ChunkInstancingMaterial,
+    material: &PreparedMaterial<StandardMaterial>,
+) -> AlphaMode {
+    if material_handle.alpha_mask {
+        AlphaMode::Mask(0.0) //Cutoff comes from the chunk uniform
+    } else {
+        material.properties.alpha_mode
+    }
+}
+
+//Not using Handle<StandardMaterial> directly on the render entity, otherwise bevy would also queue the chunk as a normal pbr mesh
+#[derive(Component, Clone)]
+pub struct ChunkInstancingMaterial {
+    handle: Handle<StandardMaterial>,
+    alpha_mask: bool, //Overrides the alpha mode of the material
+    alpha_to_coverage: bool,
+    billboard: bool, //Impostor of ChunkLod is active
+    sway_vertex_colors: bool,
 }
 
 impl ChunkInstancing {
     fn to_raw_instances(&self) -> GpuInstances {
-        GpuInstances(
-            (&self.instances)
-                .into_iter()
-                .map(|v| GpuInstance {
-                    pos_xyz: v.pos_xyz.clone(),
-                })
-                .collect(),
-        )
+        let mut data = Vec::with_capacity(self.instances.len() * self.instance_format().stride());
+        for instance in &self.instances {
+            data.extend_from_slice(&instance.pos_xyz);
+            if self.instance_layout == InstanceLayout::Full {
+                data.extend_from_slice(&instance.rotation.to_array());
+                data.extend_from_slice(&instance.scale.extend(0.0).to_array());
+            }
+            if self.tinted {
+                data.extend_from_slice(&instance.tint.as_linear_rgba_f32());
+            }
+        }
+        GpuInstances {
+            data,
+            nr_instances: self.instances.len(),
+        }
     }
-    fn to_raw_chunk_bind_group(&self) -> GpuChunkBindGroupData {
+    fn to_raw_chunk_bind_group(&self, impostor: Option<&Impostor>) -> GpuChunkBindGroupData {
+        let model_transform = self.model_transform.compute_matrix();
+        let normal_transform = Mat3::from_mat4(model_transform).inverse().transpose();
         GpuChunkBindGroupData {
-            model_transform: self.model_transform.compute_matrix().to_cols_array_2d(),
+            model_transform: model_transform.to_cols_array_2d(),
+            normal_transform: [
+                normal_transform.x_axis.extend(0.0).to_array(),
+                normal_transform.y_axis.extend(0.0).to_array(),
+                normal_transform.z_axis.extend(0.0).to_array(),
+            ],
+            params: [
+                self.alpha_mask.unwrap_or(0.5),
+                impostor.map_or(1, |impostor| impostor.atlas_columns) as f32,
+                self.stiffness.clamp(0.0, 1.0),
+                0.0,
+            ],
         }
     }
 }
@@ -222,29 +773,607 @@ impl ChunkInstancing {
 // █░░░░░░█████████░░░░░░██░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░█████████░░░░░░██░░░░░░█░░░░░░██░░░░░░░░░░█░░░░░░░░░░░░░░█
 // ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
 
-#[derive(Component)]
 pub struct ChunkInstancingInstanceBuffer {
     buffer: Buffer,
-    length: usize,
+    capacity: usize, //Nr of floats that fit in the buffer
+    length: usize,   //Nr of instances
+    data: Vec<f32>, //Copy of what is on the gpu, used to only upload the part that changed and to fill the batches
 }
 
-fn prepare_chunk_instancing_instance_buffers(
-    mut commands: Commands,
-    query: Query<(Entity, &GpuInstances)>,
-    render_device: Res<RenderDevice>,
-) {
-    for (entity, gpu_instances) in &query {
+impl ChunkInstancingInstanceBuffer {
+    fn new(render_device: &RenderDevice, gpu_instances: GpuInstances, usage: BufferUsages) -> Self {
+        //Empty buffers are not allowed so always keep some room
+        let contents = if gpu_instances.data.is_empty() {
+            vec![0.0; 4]
+        } else {
+            gpu_instances.data.clone()
+        };
         let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("instance data buffer"),
-            contents: bytemuck::cast_slice(gpu_instances.0.as_slice()),
-            usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+            contents: bytemuck::cast_slice(contents.as_slice()),
+            usage,
         });
-        commands
-            .entity(entity)
-            .insert(ChunkInstancingInstanceBuffer {
-                buffer,
-                length: gpu_instances.0.len(),
+        Self {
+            buffer,
+            capacity: contents.len(),
+            length: gpu_instances.nr_instances,
+            data: gpu_instances.data,
+        }
+    }
+
+    //Only writes the range of data that differ from the previous upload
+    fn write(&mut self, render_queue: &RenderQueue, gpu_instances: GpuInstances) {
+        let data = gpu_instances.data;
+        let differs = |(old, new): (&f32, &f32)| old.to_bits() != new.to_bits();
+        let first_changed = self
+            .data
+            .iter()
+            .zip(data.iter())
+            .position(differs)
+            .unwrap_or(self.data.len().min(data.len()));
+        let last_changed = if data.len() == self.data.len() {
+            self.data
+                .iter()
+                .zip(data.iter())
+                .rposition(differs)
+                .map_or(first_changed, |i| i + 1)
+        } else {
+            data.len()
+        };
+
+        if first_changed < last_changed {
+            render_queue.write_buffer(
+                &self.buffer,
+                (first_changed * std::mem::size_of::<f32>()) as u64,
+                bytemuck::cast_slice(&data[first_changed..last_changed]),
+            );
+        }
+        self.length = gpu_instances.nr_instances;
+        self.data = data;
+    }
+}
+
+//Lives across frames, keyed by the main world entity
+#[derive(Resource, Default)]
+pub struct ChunkInstancingInstanceBuffers {
+    buffers: HashMap<Entity, ChunkInstancingInstanceBuffer>,
+    changed: HashSet<Entity>, //Uploaded this frame, the batches containing them are rebuilt
+}
+
+pub(crate) fn prepare_chunk_instancing_instance_buffers(
+    mut extracted_instances: ResMut<ExtractedChunkInstances>,
+    mut instance_buffers: ResMut<ChunkInstancingInstanceBuffers>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+) {
+    let usage = BufferUsages::VERTEX | BufferUsages::COPY_DST;
+    instance_buffers.changed.clear();
+
+    for entity in extracted_instances.removed.drain(..) {
+        instance_buffers.buffers.remove(&entity);
+    }
+
+    for (entity, gpu_instances) in extracted_instances.changed.drain(..) {
+        instance_buffers.changed.insert(entity);
+        match instance_buffers.buffers.get_mut(&entity) {
+            Some(instance_buffer) if instance_buffer.capacity >= gpu_instances.data.len() => {
+                instance_buffer.write(&render_queue, gpu_instances);
+            }
+            _ => {
+                instance_buffers.buffers.insert(
+                    entity,
+                    ChunkInstancingInstanceBuffer::new(&render_device, gpu_instances, usage),
+                );
+            }
+        }
+    }
+}
+
+//Chunks that look the same apart from their transform and instances, drawn together by one ChunkBatch
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct ChunkBatchKey {
+    mesh: HandleId,
+    material: HandleId,
+    alpha_mask: bool,
+    alpha_to_coverage: bool,
+    billboard: bool,
+    sway_vertex_colors: bool,
+    instance_format: InstanceFormat,
+    chunk_data: [u32; 32], //Bits of the chunk uniform, a batch has one for all its chunks
+    sorted_chunk: Option<Entity>, //Blended chunks are sorted back to front, so they get a batch each
+}
+
+//Where a chunk's instances are in the batch
+pub struct ChunkDraw {
+    pub(crate) first_instance: u32,
+    pub(crate) nr_instances: u32,
+    capacity: u32, //Instances that fit in the slot, the chunk keeps its slot as long as it fits
+    pub(crate) visible: bool, //Hidden chunks keep their slot but draw no instances
+}
+
+//Every chunk drawn by the batch gets a slot in one shared instance buffer, every instance with the index of its slot added at the end.
+//Hidden chunks keep their slot, so showing and hiding chunks only changes the draw args and nothing is copied around.
+//Drawn with one multi draw, or one indirect draw per visible chunk where multi draw or indirect first_instance is missing.
+pub struct ChunkBatch {
+    key: ChunkBatchKey,
+    pub(crate) id: u64, //Stays the same for the lifetime of the batch, unlike which chunk gets queued
+    pub(crate) chunks: Vec<Option<Entity>>, //Chunk in each slot, None for free slots
+    pub(crate) draws: Vec<ChunkDraw>, //One per slot
+    pub(crate) instance_buffer: Buffer,
+    pub(crate) capacity: usize, //Nr of floats that fit in the instance buffer
+    pub(crate) stride: usize,   //Floats per instance, the instance format plus the slot index
+    pub(crate) nr_instances: u32, //End of the last slot
+    dirty_slots: Vec<usize>,    //Slots whose instances have to be written
+    indirect_buffer: Buffer,
+    draw_capacity: usize, //Nr of draws that fit in the indirect buffer
+    written_args: Vec<u32>,
+    count: u32, //Index count of the mesh, or vertex count if it's not indexed
+    indexed: bool,
+    pub(crate) multi_draw: bool,
+    chunk_buffer: Buffer, //Chunk uniform, the same for every chunk of the batch
+    chunk_meshes: Vec<f32>, //Transform and inverse transpose of every slot, the chunks move independently
+    chunk_meshes_buffer: Buffer,
+    chunk_meshes_capacity: usize, //Nr of slots that fit in the chunk meshes buffer
+    bind_group: Option<BindGroup>, //Recreated when one of its buffers is
+}
+
+const CHUNK_MESH_FLOATS: usize = 32; //Two mat4x4, see ChunkMesh in the shader
+
+impl ChunkBatch {
+    fn new(
+        render_device: &RenderDevice,
+        key: ChunkBatchKey,
+        id: u64,
+        gpu_mesh: &GpuMesh,
+        usage: BufferUsages,
+        multi_draw: bool,
+    ) -> Self {
+        let (count, indexed) = match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed { count, .. } => (*count, true),
+            GpuBufferInfo::NonIndexed => (gpu_mesh.vertex_count, false),
+        };
+        let stride = key.instance_format.stride() + 1;
+        let chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("Chunk_batch_buffer"),
+            contents: bytemuck::cast_slice(&key.chunk_data),
+            usage: BufferUsages::UNIFORM,
+        });
+        Self {
+            key,
+            id,
+            chunks: Vec::new(),
+            draws: Vec::new(),
+            instance_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("chunk batch instance buffer"),
+                size: (stride * std::mem::size_of::<f32>()) as u64,
+                usage,
+                mapped_at_creation: false,
+            }),
+            capacity: stride,
+            stride,
+            nr_instances: 0,
+            dirty_slots: Vec::new(),
+            indirect_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("chunk batch indirect buffer"),
+                size: (5 * std::mem::size_of::<u32>()) as u64,
+                usage: BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            draw_capacity: 1,
+            written_args: Vec::new(),
+            count,
+            indexed,
+            multi_draw,
+            chunk_buffer,
+            chunk_meshes: Vec::new(),
+            chunk_meshes_buffer: render_device.create_buffer(&BufferDescriptor {
+                label: Some("Chunk_batch_meshes_buffer"),
+                size: (CHUNK_MESH_FLOATS * std::mem::size_of::<f32>()) as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            }),
+            chunk_meshes_capacity: 1,
+            bind_group: None,
+        }
+    }
+
+    pub(crate) fn instance_format(&self) -> InstanceFormat {
+        self.key.instance_format
+    }
+
+    //Size of the draw args of one chunk in u32s, non indexed draws have no base vertex
+    pub(crate) fn args_len(&self) -> usize {
+        if self.indexed {
+            5
+        } else {
+            4
+        }
+    }
+
+    //Index of the instance count of a draw, the same for indexed and non indexed draws
+    pub(crate) fn instance_count_index(&self, draw_index: usize) -> u32 {
+        (draw_index * self.args_len() + 1) as u32
+    }
+
+    //Draw args of all slots, with every instance of the visible chunks or with no instances for the gpu culling to count up
+    pub(crate) fn draw_args(&self, with_instances: bool) -> Vec<u32> {
+        let mut args = Vec::with_capacity(self.draws.len() * self.args_len());
+        for draw in &self.draws {
+            let nr_instances = if with_instances && draw.visible {
+                draw.nr_instances
+            } else {
+                0
+            };
+            //Without multi draw the instance buffer is offset to the chunk instead
+            let first_instance = if self.multi_draw {
+                draw.first_instance
+            } else {
+                0
+            };
+            if self.indexed {
+                args.extend([self.count, nr_instances, 0, 0, first_instance]);
+            } else {
+                args.extend([self.count, nr_instances, 0, first_instance]);
+            }
+        }
+        args
+    }
+
+    //Puts the chunk in the first free slot it fits in, or in a new one at the end
+    fn insert(&mut self, entity: Entity, nr_instances: u32) -> usize {
+        let free_slot = self
+            .chunks
+            .iter()
+            .zip(&self.draws)
+            .position(|(chunk, draw)| chunk.is_none() && draw.capacity >= nr_instances);
+        let slot = match free_slot {
+            Some(slot) => {
+                self.chunks[slot] = Some(entity);
+                self.draws[slot].nr_instances = nr_instances;
+                slot
+            }
+            None => {
+                self.chunks.push(Some(entity));
+                self.draws.push(ChunkDraw {
+                    first_instance: self.nr_instances,
+                    nr_instances,
+                    capacity: nr_instances,
+                    visible: false,
+                });
+                self.nr_instances += nr_instances;
+                self.chunks.len() - 1
+            }
+        };
+        self.dirty_slots.push(slot);
+        slot
+    }
+
+    fn remove(&mut self, slot: usize) {
+        self.chunks[slot] = None;
+        self.draws[slot].nr_instances = 0;
+        self.draws[slot].visible = false;
+    }
+
+    //The instances of a chunk changed, it moves to another slot if they don't fit anymore
+    fn update(&mut self, slot: usize, entity: Entity, nr_instances: u32) -> usize {
+        if nr_instances <= self.draws[slot].capacity {
+            self.draws[slot].nr_instances = nr_instances;
+            self.dirty_slots.push(slot);
+            return slot;
+        }
+        self.remove(slot);
+        self.insert(entity, nr_instances)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.chunks.iter().all(Option::is_none)
+    }
+
+    //Free slots at the end give their instances back
+    fn trim(&mut self) {
+        while let Some(None) = self.chunks.last() {
+            self.chunks.pop();
+            let draw = self.draws.pop().unwrap();
+            self.nr_instances = draw.first_instance;
+        }
+        self.dirty_slots.retain(|slot| *slot < self.chunks.len());
+    }
+
+    fn set_chunk_mesh(&mut self, slot: usize, mesh_uniform: &MeshUniform) {
+        let start = slot * CHUNK_MESH_FLOATS;
+        if self.chunk_meshes.len() < start + CHUNK_MESH_FLOATS {
+            self.chunk_meshes.resize(start + CHUNK_MESH_FLOATS, 0.0);
+        }
+        self.chunk_meshes[start..start + 16]
+            .copy_from_slice(&mesh_uniform.transform.to_cols_array());
+        self.chunk_meshes[start + 16..start + CHUNK_MESH_FLOATS]
+            .copy_from_slice(&mesh_uniform.inverse_transpose_model.to_cols_array());
+    }
+
+    //Instances of a chunk with the slot index added to each
+    fn slot_data(&self, slot: usize, instance_buffer: &ChunkInstancingInstanceBuffer) -> Vec<f32> {
+        let mut data = Vec::with_capacity(instance_buffer.length * self.stride);
+        for instance in instance_buffer
+            .data
+            .chunks_exact(self.key.instance_format.stride())
+        {
+            data.extend_from_slice(instance);
+            data.push(slot as f32);
+        }
+        data
+    }
+
+    //Writes what changed this frame, only the buffers that outgrew their size are recreated
+    fn upload(
+        &mut self,
+        render_device: &RenderDevice,
+        render_queue: &RenderQueue,
+        instance_buffers: &ChunkInstancingInstanceBuffers,
+        chunk_batch_bind_group_layout: &BindGroupLayout,
+        wind_buffer: &WindBuffer,
+    ) {
+        let needed = self.nr_instances as usize * self.stride;
+        if needed > self.capacity {
+            //Grow ahead so adding chunks one by one doesn't reallocate every frame, every slot is written again
+            self.capacity = needed.max(self.capacity * 2);
+            self.instance_buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("chunk batch instance buffer"),
+                size: (self.capacity * std::mem::size_of::<f32>()) as u64,
+                usage: self.instance_buffer.usage(),
+                mapped_at_creation: false,
+            });
+            self.dirty_slots = (0..self.chunks.len()).collect();
+            self.bind_group = None;
+        }
+        self.dirty_slots.sort_unstable();
+        self.dirty_slots.dedup();
+        for slot in std::mem::take(&mut self.dirty_slots) {
+            let Some(instance_buffer) =
+                self.chunks[slot].and_then(|entity| instance_buffers.buffers.get(&entity))
+            else {
+                continue;
+            };
+            let data = self.slot_data(slot, instance_buffer);
+            let offset = self.draws[slot].first_instance as usize * self.stride;
+            render_queue.write_buffer(
+                &self.instance_buffer,
+                (offset * std::mem::size_of::<f32>()) as u64,
+                bytemuck::cast_slice(&data),
+            );
+        }
+
+        let args = self.draw_args(true);
+        if self.draws.len() > self.draw_capacity {
+            self.draw_capacity = self.draws.len().max(self.draw_capacity * 2);
+            self.indirect_buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("chunk batch indirect buffer"),
+                size: (self.draw_capacity * self.args_len() * std::mem::size_of::<u32>()) as u64,
+                usage: BufferUsages::INDIRECT | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
             });
+            self.written_args.clear();
+        }
+        if args != self.written_args {
+            render_queue.write_buffer(&self.indirect_buffer, 0, bytemuck::cast_slice(&args));
+            self.written_args = args;
+        }
+
+        //Chunk transforms can change every frame without touching the instances
+        if self.chunks.len() > self.chunk_meshes_capacity {
+            self.chunk_meshes_capacity = self.chunks.len().max(self.chunk_meshes_capacity * 2);
+            self.chunk_meshes_buffer = render_device.create_buffer(&BufferDescriptor {
+                label: Some("Chunk_batch_meshes_buffer"),
+                size: (self.chunk_meshes_capacity * CHUNK_MESH_FLOATS * std::mem::size_of::<f32>())
+                    as u64,
+                usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                mapped_at_creation: false,
+            });
+            self.bind_group = None;
+        }
+        self.chunk_meshes
+            .resize(self.chunks.len() * CHUNK_MESH_FLOATS, 0.0);
+        render_queue.write_buffer(
+            &self.chunk_meshes_buffer,
+            0,
+            bytemuck::cast_slice(&self.chunk_meshes),
+        );
+
+        if self.bind_group.is_none() {
+            self.bind_group = Some(render_device.create_bind_group(&BindGroupDescriptor {
+                label: Some("Chunk_batch_bindgroup"),
+                layout: chunk_batch_bind_group_layout,
+                entries: &[
+                    BindGroupEntry {
+                        binding: 0,
+                        resource: self.chunk_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 1,
+                        resource: self.chunk_meshes_buffer.as_entire_binding(),
+                    },
+                    BindGroupEntry {
+                        binding: 2,
+                        resource: wind_buffer.as_entire_binding(),
+                    },
+                ],
+            }));
+        }
+    }
+}
+
+//Lives across frames, a batch is only dropped when the last chunk in it is gone for good or moved to another batch
+#[derive(Resource, Default)]
+pub struct ChunkBatches {
+    batches: HashMap<ChunkBatchKey, ChunkBatch>,
+    slots: HashMap<Entity, (ChunkBatchKey, usize)>, //Batch and slot of every batched chunk, hidden ones included
+    queued: HashMap<Entity, ChunkBatchKey>, //First visible chunk of each batch, the one that gets queued and draws all of them
+    batched: HashSet<Entity>,               //Visible chunks drawn by a batch
+    next_id: u64,
+}
+
+impl ChunkBatches {
+    //Batch drawn by the queued chunk
+    pub(crate) fn get(&self, entity: &Entity) -> Option<&ChunkBatch> {
+        self.queued
+            .get(entity)
+            .and_then(|key| self.batches.get(key))
+    }
+
+    //Batches with a visible chunk, with the chunk that is queued for them
+    pub(crate) fn iter_queued(&self) -> impl Iterator<Item = (&Entity, &ChunkBatch)> {
+        self.queued
+            .iter()
+            .filter_map(|(entity, key)| Some((entity, self.batches.get(key)?)))
+    }
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+pub(crate) fn prepare_chunk_batches(
+    mut chunk_batches: ResMut<ChunkBatches>,
+    instance_buffers: Res<ChunkInstancingInstanceBuffers>,
+    custom_pipeline: Res<CustomPipeline>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<StandardMaterial>>,
+    culling_pipeline: Option<Res<InstanceCullingPipeline>>,
+    wind_buffer: Res<WindBuffer>,
+    chunks: Query<(
+        Entity,
+        &MeshUniform,
+        &Handle<Mesh>,
+        &ChunkInstancingMaterial,
+        &InstanceFormat,
+        &GpuChunkBindGroupData,
+    )>,
+) {
+    //Not supported without storage buffers (webgl2), every chunk is drawn on its own then
+    let Some(chunk_batch_bind_group_layout) = &custom_pipeline.chunk_batch_bind_group_layout else {
+        return;
+    };
+    let multi_draw = render_device
+        .features()
+        .contains(WgpuFeatures::MULTI_DRAW_INDIRECT | WgpuFeatures::INDIRECT_FIRST_INSTANCE);
+    //The gpu culling reads the instances as a storage buffer
+    let usage = if culling_pipeline.is_some() {
+        BufferUsages::VERTEX | BufferUsages::COPY_DST | BufferUsages::STORAGE
+    } else {
+        BufferUsages::VERTEX | BufferUsages::COPY_DST
+    };
+
+    let ChunkBatches {
+        batches,
+        slots,
+        queued,
+        batched,
+        next_id,
+    } = chunk_batches.as_mut();
+
+    //Chunks that are gone for good give their slot back
+    slots.retain(|entity, (key, slot)| {
+        if instance_buffers.buffers.contains_key(entity) {
+            return true;
+        }
+        if let Some(batch) = batches.get_mut(key) {
+            batch.remove(*slot);
+        }
+        false
+    });
+
+    //Instances can change while the chunk is hidden too
+    for entity in &instance_buffers.changed {
+        if let Some((key, slot)) = slots.get_mut(entity) {
+            let nr_instances = instance_buffers.buffers[entity].length as u32;
+            *slot = batches
+                .get_mut(key)
+                .unwrap()
+                .update(*slot, *entity, nr_instances);
+        }
+    }
+
+    for batch in batches.values_mut() {
+        batch.draws.iter_mut().for_each(|draw| draw.visible = false);
+    }
+    batched.clear();
+
+    for (entity, mesh_uniform, mesh_handle, material_handle, instance_format, gpu_chunk) in &chunks
+    {
+        let (Some(material), Some(instance_buffer), Some(gpu_mesh)) = (
+            render_materials.get(&material_handle.handle),
+            instance_buffers.buffers.get(&entity),
+            meshes.get(mesh_handle),
+        ) else {
+            continue;
+        };
+        if instance_buffer.length == 0 {
+            continue;
+        }
+        let blended = !matches!(
+            chunk_alpha_mode(material_handle, material),
+            AlphaMode::Opaque | AlphaMode::Mask(_)
+        );
+        let key = ChunkBatchKey {
+            mesh: mesh_handle.id(),
+            material: material_handle.handle.id(),
+            alpha_mask: material_handle.alpha_mask,
+            alpha_to_coverage: material_handle.alpha_to_coverage,
+            billboard: material_handle.billboard,
+            sway_vertex_colors: material_handle.sway_vertex_colors,
+            instance_format: *instance_format,
+            chunk_data: bytemuck::cast(gpu_chunk.to_raw_uniform()),
+            sorted_chunk: blended.then_some(entity),
+        };
+
+        //A chunk only moves to another batch when its mesh, material etc. change, e.g when the LOD switches
+        let slot = match slots.get(&entity) {
+            Some((slot_key, slot)) if *slot_key == key => *slot,
+            _ => {
+                if let Some((previous_key, previous_slot)) = slots.remove(&entity) {
+                    if let Some(batch) = batches.get_mut(&previous_key) {
+                        batch.remove(previous_slot);
+                    }
+                }
+                let batch = batches.entry(key.clone()).or_insert_with(|| {
+                    *next_id += 1;
+                    ChunkBatch::new(
+                        &render_device,
+                        key.clone(),
+                        *next_id,
+                        gpu_mesh,
+                        usage,
+                        multi_draw,
+                    )
+                });
+                let slot = batch.insert(entity, instance_buffer.length as u32);
+                slots.insert(entity, (key.clone(), slot));
+                slot
+            }
+        };
+        let batch = batches.get_mut(&key).unwrap();
+        batch.draws[slot].visible = true;
+        batch.set_chunk_mesh(slot, mesh_uniform);
+        batched.insert(entity);
+    }
+
+    batches.retain(|_, batch| !batch.is_empty());
+    queued.clear();
+    for (key, batch) in batches.iter_mut() {
+        batch.trim();
+        batch.upload(
+            &render_device,
+            &render_queue,
+            &instance_buffers,
+            chunk_batch_bind_group_layout,
+            &wind_buffer,
+        );
+        let first_visible = batch
+            .chunks
+            .iter()
+            .zip(&batch.draws)
+            .find_map(|(chunk, draw)| chunk.filter(|_| draw.visible));
+        if let Some(entity) = first_visible {
+            queued.insert(entity, key.clone());
+        }
     }
 }
 
@@ -256,58 +1385,32 @@ fn prepare_grass_chunk_bind_group(
     query: Query<(Entity, &GpuChunkBindGroupData)>,
     render_device: Res<RenderDevice>,
     custom_pipeline: Res<CustomPipeline>,
+    wind_buffer: Res<WindBuffer>,
 ) {
     for (entity, gpu_chunk) in &query {
         let chunk_buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
             label: Some("Chunk_instancing_buffer"),
-            contents: bytemuck::cast_slice(&[gpu_chunk.model_transform]),
+            contents: bytemuck::cast_slice(&gpu_chunk.to_raw_uniform()),
             usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
         });
 
         let chunk_instancing_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
             label: Some("Chunk_instancing_bindgroup"),
             layout: &custom_pipeline.chunk_instancing_bind_group_layout,
-            entries: &[BindGroupEntry {
-                binding: 0,
-                resource: chunk_buffer.as_entire_binding(),
-            }],
-        });
-        commands
-            .entity(entity)
-            .insert(ChunkInstancingBindGroup(chunk_instancing_bind_group));
-    }
-}
-
-#[derive(Component)]
-pub struct TextureBindGroup(BindGroup);
-
-pub fn prepare_textures_bind_group(
-    render_device: Res<RenderDevice>,
-    mut commands: Commands,
-    custom_pipeline: Res<CustomPipeline>,
-    image_query: Query<(Entity, &Handle<Image>), With<GpuInstances>>,
-    gpu_images: Res<RenderAssets<Image>>,
-) {
-    for (e, texture_handle) in image_query.iter() {
-        let gpu_image = gpu_images.get(&texture_handle.clone()).unwrap();
-
-        let texture_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
-            layout: &custom_pipeline.texture_bind_group_layout,
             entries: &[
                 BindGroupEntry {
                     binding: 0,
-                    resource: BindingResource::TextureView(&gpu_image.texture_view),
+                    resource: chunk_buffer.as_entire_binding(),
                 },
                 BindGroupEntry {
-                    binding: 1,
-                    resource: BindingResource::Sampler(&gpu_image.sampler),
+                    binding: 2,
+                    resource: wind_buffer.as_entire_binding(),
                 },
             ],
-            label: Some("growth_texture_bind_group"),
         });
         commands
-            .entity(e)
-            .insert(TextureBindGroup(texture_bind_group));
+            .entity(entity)
+            .insert(ChunkInstancingBindGroup(chunk_instancing_bind_group));
     }
 }
 
@@ -325,47 +1428,292 @@ pub fn prepare_textures_bind_group(
 // █░░░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█
 // ██████████████████████████████████████████████████████████████████████████████
 
-#[allow(clippy::too_many_arguments)]
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
 fn queue_custom(
+    opaque_3d_draw_functions: Res<DrawFunctions<Opaque3d>>,
+    alpha_mask_3d_draw_functions: Res<DrawFunctions<AlphaMask3d>>,
     transparent_3d_draw_functions: Res<DrawFunctions<Transparent3d>>,
     custom_pipeline: Res<CustomPipeline>,
     msaa: Res<Msaa>,
     mut pipelines: ResMut<SpecializedMeshPipelines<CustomPipeline>>,
     pipeline_cache: Res<PipelineCache>,
     meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<StandardMaterial>>,
     material_meshes: Query<
-        (Entity, &MeshUniform, &Handle<Mesh>, &Handle<Image>),
-        With<GpuInstances>,
+        (
+            Entity,
+            &MeshUniform,
+            &Handle<Mesh>,
+            &ChunkInstancingMaterial,
+            &InstanceFormat,
+        ),
+        With<GpuChunkBindGroupData>,
     >,
-    mut views: Query<(&ExtractedView, &mut RenderPhase<Transparent3d>)>,
-    gpu_images: Res<RenderAssets<Image>>,
+    chunk_batches: Res<ChunkBatches>,
+    mut views: Query<(
+        &ExtractedView,
+        Option<&Tonemapping>,
+        Option<&DebandDither>,
+        Option<&EnvironmentMapLight>,
+        Option<&ScreenSpaceAmbientOcclusionSettings>,
+        &mut RenderPhase<Opaque3d>,
+        &mut RenderPhase<AlphaMask3d>,
+        &mut RenderPhase<Transparent3d>,
+    )>,
+    images: Res<RenderAssets<Image>>,
 ) {
-    let draw_custom = transparent_3d_draw_functions
+    let draw_custom_opaque = opaque_3d_draw_functions
         .read()
         .get_id::<DrawCustom>()
         .unwrap();
+    let draw_custom_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<DrawCustom>()
+        .unwrap();
+    let draw_custom_transparent = transparent_3d_draw_functions
+        .read()
+        .get_id::<DrawCustom>()
+        .unwrap();
+    let draw_batch_opaque = opaque_3d_draw_functions
+        .read()
+        .get_id::<DrawCustomBatch>()
+        .unwrap();
+    let draw_batch_alpha_mask = alpha_mask_3d_draw_functions
+        .read()
+        .get_id::<DrawCustomBatch>()
+        .unwrap();
+    let draw_batch_transparent = transparent_3d_draw_functions
+        .read()
+        .get_id::<DrawCustomBatch>()
+        .unwrap();
 
-    let msaa_key = MeshPipelineKey::from_msaa_samples(msaa.samples());
-
-    for (view, mut transparent_phase) in &mut views {
-        let view_key = msaa_key | MeshPipelineKey::from_hdr(view.hdr);
+    for (
+        view,
+        tonemapping,
+        dither,
+        environment_map,
+        ssao,
+        mut opaque_phase,
+        mut alpha_mask_phase,
+        mut transparent_phase,
+    ) in &mut views
+    {
+        let view_key = pbr_view_key(
+            &msaa,
+            view,
+            tonemapping,
+            dither,
+            environment_map,
+            ssao,
+            &images,
+        );
         let rangefinder = view.rangefinder3d();
-        for (entity, mesh_uniform, mesh_handle, image_handle) in &material_meshes {
-            if let (Some(mesh), Some(_)) = (
+        for (entity, mesh_uniform, mesh_handle, material_handle, instance_format) in
+            &material_meshes
+        {
+            //The first visible chunk of a batch draws all of them
+            let batched = chunk_batches.queued.contains_key(&entity);
+            if !batched && chunk_batches.batched.contains(&entity) {
+                continue;
+            }
+            if let (Some(mesh), Some(material)) = (
                 meshes.get(mesh_handle),
-                gpu_images.get(&image_handle.clone()),
+                render_materials.get(&material_handle.handle),
             ) {
-                let key =
+                let mut mesh_key =
                     view_key | MeshPipelineKey::from_primitive_topology(mesh.primitive_topology);
-                let pipeline = pipelines
-                    .specialize(&pipeline_cache, &custom_pipeline, key, &mesh.layout)
-                    .unwrap();
-                transparent_phase.add(Transparent3d {
-                    entity,
-                    pipeline,
-                    draw_function: draw_custom,
-                    distance: rangefinder.distance(&mesh_uniform.transform),
-                });
+                let alpha_mode = chunk_alpha_mode(material_handle, material);
+                match alpha_mode {
+                    AlphaMode::Blend => {
+                        mesh_key |= MeshPipelineKey::BLEND_ALPHA;
+                    }
+                    AlphaMode::Premultiplied | AlphaMode::Add => {
+                        mesh_key |= MeshPipelineKey::BLEND_PREMULTIPLIED_ALPHA;
+                    }
+                    AlphaMode::Multiply => {
+                        mesh_key |= MeshPipelineKey::BLEND_MULTIPLY;
+                    }
+                    AlphaMode::Mask(_) => {
+                        mesh_key |= MeshPipelineKey::MAY_DISCARD;
+                    }
+                    _ => (),
+                }
+
+                let key = ChunkInstancingPipelineKey {
+                    mesh_key,
+                    instance_format: *instance_format,
+                    material_key: material.key.clone(),
+                    alpha_mask: material_handle.alpha_mask,
+                    alpha_to_coverage: material_handle.alpha_to_coverage
+                        && matches!(alpha_mode, AlphaMode::Mask(_)),
+                    billboard: material_handle.billboard,
+                    sway_vertex_colors: material_handle.sway_vertex_colors,
+                    batched,
+                };
+                let pipeline = match pipelines.specialize(
+                    &pipeline_cache,
+                    &custom_pipeline,
+                    key,
+                    &mesh.layout,
+                ) {
+                    Ok(pipeline) => pipeline,
+                    Err(err) => {
+                        error!("{}", err);
+                        continue;
+                    }
+                };
+                let distance =
+                    rangefinder.distance(&mesh_uniform.transform) + material.properties.depth_bias;
+                //Only materials that really blend end up in the sorted transparent phase
+                match alpha_mode {
+                    AlphaMode::Opaque => {
+                        opaque_phase.add(Opaque3d {
+                            entity,
+                            pipeline,
+                            draw_function: if batched {
+                                draw_batch_opaque
+                            } else {
+                                draw_custom_opaque
+                            },
+                            distance,
+                        });
+                    }
+                    AlphaMode::Mask(_) => {
+                        alpha_mask_phase.add(AlphaMask3d {
+                            entity,
+                            pipeline,
+                            draw_function: if batched {
+                                draw_batch_alpha_mask
+                            } else {
+                                draw_custom_alpha_mask
+                            },
+                            distance,
+                        });
+                    }
+                    _ => {
+                        transparent_phase.add(Transparent3d {
+                            entity,
+                            pipeline,
+                            draw_function: if batched {
+                                draw_batch_transparent
+                            } else {
+                                draw_custom_transparent
+                            },
+                            distance,
+                        });
+                    }
+                }
+            }
+        }
+    }
+}
+
+//Same as bevy's queue_shadows but for the instanced chunks
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn queue_custom_shadows(
+    shadow_draw_functions: Res<DrawFunctions<Shadow>>,
+    shadow_pipeline: Res<CustomShadowPipeline>,
+    mut pipelines: ResMut<SpecializedMeshPipelines<CustomShadowPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    meshes: Res<RenderAssets<Mesh>>,
+    render_materials: Res<RenderMaterials<StandardMaterial>>,
+    casting_chunks: Query<
+        (&Handle<Mesh>, &ChunkInstancingMaterial, &InstanceFormat),
+        (With<GpuChunkBindGroupData>, Without<NotShadowCaster>),
+    >,
+    view_lights: Query<(Entity, &ViewLightEntities)>,
+    mut view_light_shadow_phases: Query<(&LightEntity, &mut RenderPhase<Shadow>)>,
+    point_light_entities: Query<&CubemapVisibleEntities, With<ExtractedPointLight>>,
+    directional_light_entities: Query<&CascadesVisibleEntities, With<ExtractedDirectionalLight>>,
+    spot_light_entities: Query<&VisibleEntities, With<ExtractedPointLight>>,
+) {
+    let draw_custom_shadow = shadow_draw_functions
+        .read()
+        .get_id::<DrawCustomShadow>()
+        .unwrap();
+
+    for (view_entity, view_lights) in &view_lights {
+        for view_light_entity in view_lights.lights.iter().copied() {
+            let Ok((light_entity, mut shadow_phase)) =
+                view_light_shadow_phases.get_mut(view_light_entity)
+            else {
+                continue;
+            };
+            //Lights with shadows disabled have no visible entities so nothing gets queued for them
+            let visible_entities = match light_entity {
+                LightEntity::Directional {
+                    light_entity,
+                    cascade_index,
+                } => directional_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .and_then(|cascades| cascades.entities.get(&view_entity))
+                    .and_then(|cascades| cascades.get(*cascade_index)),
+                LightEntity::Point {
+                    light_entity,
+                    face_index,
+                } => point_light_entities
+                    .get(*light_entity)
+                    .ok()
+                    .map(|cubemap| cubemap.get(*face_index)),
+                LightEntity::Spot { light_entity } => spot_light_entities.get(*light_entity).ok(),
+            };
+            let Some(visible_entities) = visible_entities else {
+                continue;
+            };
+            let is_directional_light = matches!(light_entity, LightEntity::Directional { .. });
+
+            for entity in visible_entities.iter().copied() {
+                let Ok((mesh_handle, material_handle, instance_format)) =
+                    casting_chunks.get(entity)
+                else {
+                    continue;
+                };
+                if let (Some(mesh), Some(material)) = (
+                    meshes.get(mesh_handle),
+                    render_materials.get(&material_handle.handle),
+                ) {
+                    let mut mesh_key =
+                        MeshPipelineKey::from_primitive_topology(mesh.primitive_topology)
+                            | MeshPipelineKey::DEPTH_PREPASS;
+                    if is_directional_light {
+                        mesh_key |= MeshPipelineKey::DEPTH_CLAMP_ORTHO;
+                    }
+                    if material_handle.alpha_mask
+                        || matches!(material.properties.alpha_mode, AlphaMode::Mask(_))
+                    {
+                        mesh_key |= MeshPipelineKey::MAY_DISCARD;
+                    }
+
+                    let key = ChunkInstancingPipelineKey {
+                        mesh_key,
+                        instance_format: *instance_format,
+                        material_key: material.key.clone(),
+                        alpha_mask: material_handle.alpha_mask,
+                        alpha_to_coverage: false,
+                        billboard: material_handle.billboard,
+                        sway_vertex_colors: material_handle.sway_vertex_colors,
+                        batched: false,
+                    };
+                    let pipeline = match pipelines.specialize(
+                        &pipeline_cache,
+                        &shadow_pipeline,
+                        key,
+                        &mesh.layout,
+                    ) {
+                        Ok(pipeline) => pipeline,
+                        Err(err) => {
+                            error!("{}", err);
+                            continue;
+                        }
+                    };
+                    shadow_phase.add(Shadow {
+                        draw_function: draw_custom_shadow,
+                        pipeline,
+                        entity,
+                        distance: 0.0,
+                    });
+                }
             }
         }
     }
@@ -388,9 +1736,10 @@ fn queue_custom(
 #[derive(Resource)]
 pub struct CustomPipeline {
     shader: Handle<Shader>,
-    mesh_pipeline: MeshPipeline,
+    _functions_shader: Handle<Shader>, //Only held so the shared shader module stays loaded, it's imported by both shaders
+    material_pipeline: MaterialPipeline<StandardMaterial>,
     chunk_instancing_bind_group_layout: BindGroupLayout,
-    texture_bind_group_layout: BindGroupLayout,
+    chunk_batch_bind_group_layout: Option<BindGroupLayout>, //None where storage buffers are missing (webgl2)
 }
 
 impl FromWorld for CustomPipeline {
@@ -402,101 +1751,334 @@ impl FromWorld for CustomPipeline {
 
         //Instancing chunk
         let chunk_instancing_bind_group_layout =
-            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
-                entries: &[BindGroupLayoutEntry {
-                    binding: 0,
-                    visibility: ShaderStages::VERTEX,
-                    ty: BindingType::Buffer {
-                        ty: BufferBindingType::Uniform,
-                        has_dynamic_offset: false, //size will not change
-                        min_binding_size: None,
-                    },
-                    count: None,
-                }],
-                label: Some("grass_chunk_bind_group_layout"),
-            });
-
-        //Model texture
-        let texture_bind_group_layout =
             render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
                 entries: &[
                     BindGroupLayoutEntry {
                         binding: 0,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Texture {
-                            multisampled: false,
-                            view_dimension: TextureViewDimension::D2,
-                            sample_type: TextureSampleType::Float { filterable: true },
+                        visibility: ShaderStages::VERTEX_FRAGMENT,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false, //size will not change
+                            min_binding_size: None,
                         },
                         count: None,
                     },
+                    //Wind, binding 1 is left for the chunk transforms of the batch layout
                     BindGroupLayoutEntry {
-                        binding: 1,
-                        visibility: ShaderStages::FRAGMENT,
-                        ty: BindingType::Sampler(SamplerBindingType::Filtering),
+                        binding: 2,
+                        visibility: ShaderStages::VERTEX,
+                        ty: BindingType::Buffer {
+                            ty: BufferBindingType::Uniform,
+                            has_dynamic_offset: false,
+                            min_binding_size: None,
+                        },
                         count: None,
                     },
                 ],
-                label: Some("texture_bind_group_layout"),
+                label: Some("grass_chunk_bind_group_layout"),
+            });
+
+        //Chunk uniform shared by the batch plus the transforms of every chunk in it
+        let chunk_batch_bind_group_layout =
+            (render_device.limits().max_storage_buffers_per_shader_stage > 0).then(|| {
+                render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                    entries: &[
+                        BindGroupLayoutEntry {
+                            binding: 0,
+                            visibility: ShaderStages::VERTEX_FRAGMENT,
+                            ty: BindingType::Buffer {
+                                ty: BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        BindGroupLayoutEntry {
+                            binding: 1,
+                            visibility: ShaderStages::VERTEX,
+                            ty: BindingType::Buffer {
+                                ty: BufferBindingType::Storage { read_only: true },
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                        BindGroupLayoutEntry {
+                            binding: 2,
+                            visibility: ShaderStages::VERTEX,
+                            ty: BindingType::Buffer {
+                                ty: BufferBindingType::Uniform,
+                                has_dynamic_offset: false,
+                                min_binding_size: None,
+                            },
+                            count: None,
+                        },
+                    ],
+                    label: Some("chunk_batch_bind_group_layout"),
+                })
             });
 
         let asset_server = world.resource::<AssetServer>();
         // asset_server.watch_for_changes().unwrap();
         let shader = asset_server.load("shaders/chunk_instancing.wgsl");
+        let functions_shader = asset_server.load("shaders/chunk_instancing_functions.wgsl");
 
-        let mesh_pipeline = world.resource::<MeshPipeline>();
-        // let material_pipeline = world.resource::<MaterialPipeline<StandardMaterial>>();
+        let material_pipeline = world.resource::<MaterialPipeline<StandardMaterial>>();
 
         CustomPipeline {
             shader,
-            mesh_pipeline: mesh_pipeline.clone(),
-            // material_pipeline: material_pipeline.clone(),
+            _functions_shader: functions_shader,
+            material_pipeline: material_pipeline.clone(),
             chunk_instancing_bind_group_layout,
-            texture_bind_group_layout,
+            chunk_batch_bind_group_layout,
         }
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct ChunkInstancingPipelineKey {
+    pub mesh_key: MeshPipelineKey,
+    pub instance_format: InstanceFormat,
+    pub material_key: StandardMaterialKey,
+    pub alpha_mask: bool,
+    pub alpha_to_coverage: bool,
+    pub billboard: bool,
+    pub sway_vertex_colors: bool, //SwayWeights::VertexColors, only used when the mesh has vertex colors
+    pub batched: bool,            //Drawn by a ChunkBatch, instances carry the index of their chunk
+}
+
 impl SpecializedMeshPipeline for CustomPipeline {
-    type Key = MeshPipelineKey;
+    type Key = ChunkInstancingPipelineKey;
 
     fn specialize(
         &self,
         key: Self::Key,
         layout: &MeshVertexBufferLayout,
     ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
-        // let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
-        let mut descriptor = self.mesh_pipeline.specialize(key, layout)?;
-
-        // meshes typically live in bind group 2. because we are using bindgroup 1
-        // we need to add MESH_BINDGROUP_1 shader def so that the bindings are correctly
-        // linked in the shader
-        // descriptor
-        //     .vertex
-        //     .shader_defs
-        //     .push("MESH_BINDGROUP_1".into());
-        // THIS DOES NOT WORK! I had to include a dummy mesh layout in the custom pipeline and then have it at bind group 2
+        //Start from the StandardMaterial pipeline, this gives the layout [view, material, mesh] and all the material shader defs
+        let mut descriptor = self.material_pipeline.specialize(
+            MaterialPipelineKey {
+                mesh_key: key.mesh_key,
+                bind_group_data: key.material_key,
+            },
+            layout,
+        )?;
+
+        //Opaque and masked chunks write depth to get early-z, blended ones only test against it
+        if let Some(depth_stencil) = descriptor.depth_stencil.as_mut() {
+            depth_stencil.depth_write_enabled = key
+                .mesh_key
+                .intersection(MeshPipelineKey::BLEND_RESERVED_BITS)
+                == MeshPipelineKey::BLEND_OPAQUE;
+            depth_stencil.depth_compare = CompareFunction::GreaterEqual;
+        }
 
         descriptor.vertex.shader = self.shader.clone();
+        let mut instance_stride = key.instance_format.stride();
+        let mut instance_attributes = key.instance_format.vertex_attributes();
+        if key.batched {
+            instance_attributes.push(VertexAttribute {
+                format: VertexFormat::Float32,
+                offset: (instance_stride * std::mem::size_of::<f32>()) as u64,
+                shader_location: 12, // chunk index
+            });
+            instance_stride += 1;
+            descriptor.vertex.shader_defs.push("CHUNK_BATCH".into());
+        }
         descriptor.vertex.buffers.push(VertexBufferLayout {
-            array_stride: std::mem::size_of::<GpuInstance>() as u64,
+            array_stride: (instance_stride * std::mem::size_of::<f32>()) as u64,
             step_mode: VertexStepMode::Instance,
-            attributes: vec![VertexAttribute {
-                format: VertexFormat::Float32x4,
-                offset: 0,
-                shader_location: 3, // shader locations 0-2 are taken up by Position, Normal and UV attributes
-            }],
+            attributes: instance_attributes,
         });
+        if key.instance_format.layout == InstanceLayout::Full {
+            descriptor
+                .vertex
+                .shader_defs
+                .push("FULL_INSTANCE_TRANSFORM".into());
+        }
         descriptor.fragment.as_mut().unwrap().shader = self.shader.clone();
+        if key.instance_format.tinted {
+            descriptor.vertex.shader_defs.push("INSTANCE_TINT".into());
+            descriptor
+                .fragment
+                .as_mut()
+                .unwrap()
+                .shader_defs
+                .push("INSTANCE_TINT".into());
+        }
 
-        descriptor.layout = vec![
-            self.mesh_pipeline.view_layout_multisampled.clone(),
-            // self.material_pipeline.material_layout.clone(), //Have not yet gotten the material to work. Still using own fragment shader :(
-            self.mesh_pipeline.mesh_layouts.model_only.clone(), // Add this as a dummy layout to make the shader work. It seems the mesh has to be on group 2?! Dont know what is wrong.
-            self.mesh_pipeline.mesh_layouts.model_only.clone(), // TODO: should I try different?
-            self.chunk_instancing_bind_group_layout.clone(),
-            self.texture_bind_group_layout.clone(),
-        ];
+        if key.alpha_mask {
+            descriptor
+                .fragment
+                .as_mut()
+                .unwrap()
+                .shader_defs
+                .push("CHUNK_ALPHA_MASK".into());
+        }
+        if key.billboard {
+            descriptor.vertex.shader_defs.push("BILLBOARD".into());
+        }
+        if key.sway_vertex_colors && layout.contains(Mesh::ATTRIBUTE_COLOR) {
+            descriptor
+                .vertex
+                .shader_defs
+                .push("SWAY_VERTEX_COLORS".into());
+        }
+        //Alpha to coverage does nothing without msaa, fall back to the normal cutoff then
+        if key.alpha_to_coverage && key.mesh_key.msaa_samples() > 1 {
+            descriptor.multisample.alpha_to_coverage_enabled = true;
+            descriptor
+                .fragment
+                .as_mut()
+                .unwrap()
+                .shader_defs
+                .push("ALPHA_TO_COVERAGE".into());
+        }
+
+        let chunk_layout = match (&self.chunk_batch_bind_group_layout, key.batched) {
+            (Some(chunk_batch_layout), true) => chunk_batch_layout,
+            _ => &self.chunk_instancing_bind_group_layout,
+        };
+        descriptor.layout.push(chunk_layout.clone());
+
+        Ok(descriptor)
+    }
+}
+
+//Depth only pipeline for drawing the chunks into the shadow maps, layout is [prepass view, material, mesh, chunk]
+#[derive(Resource)]
+pub struct CustomShadowPipeline {
+    shader: Handle<Shader>,
+    view_layout: BindGroupLayout,
+    material_pipeline: MaterialPipeline<StandardMaterial>,
+    chunk_instancing_bind_group_layout: BindGroupLayout,
+}
+
+impl FromWorld for CustomShadowPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let asset_server = world.resource::<AssetServer>();
+        let shader = asset_server.load("shaders/chunk_instancing_prepass.wgsl");
+
+        let custom_pipeline = world.resource::<CustomPipeline>();
+        let prepass_pipeline = world.resource::<PrepassPipeline<StandardMaterial>>();
+
+        CustomShadowPipeline {
+            shader,
+            view_layout: prepass_pipeline.view_layout_no_motion_vectors.clone(),
+            material_pipeline: custom_pipeline.material_pipeline.clone(),
+            chunk_instancing_bind_group_layout: custom_pipeline
+                .chunk_instancing_bind_group_layout
+                .clone(),
+        }
+    }
+}
+
+impl SpecializedMeshPipeline for CustomShadowPipeline {
+    type Key = ChunkInstancingPipelineKey;
+
+    fn specialize(
+        &self,
+        key: Self::Key,
+        layout: &MeshVertexBufferLayout,
+    ) -> Result<RenderPipelineDescriptor, SpecializedMeshPipelineError> {
+        let mut shader_defs: Vec<ShaderDefVal> =
+            vec!["DEPTH_PREPASS".into(), "VERTEX_POSITIONS".into()];
+        let mut vertex_attributes = vec![Mesh::ATTRIBUTE_POSITION.at_shader_location(0)];
+
+        if key.mesh_key.contains(MeshPipelineKey::MAY_DISCARD) {
+            shader_defs.push("MAY_DISCARD".into());
+        }
+        //Directional lights need the unclamped depth written from the fragment shader
+        if key.mesh_key.contains(MeshPipelineKey::DEPTH_CLAMP_ORTHO) {
+            shader_defs.push("DEPTH_CLAMP_ORTHO".into());
+            shader_defs.push("PREPASS_FRAGMENT".into());
+        }
+        if layout.contains(Mesh::ATTRIBUTE_UV_0) {
+            shader_defs.push("VERTEX_UVS".into());
+            vertex_attributes.push(Mesh::ATTRIBUTE_UV_0.at_shader_location(1));
+        }
+        if key.instance_format.layout == InstanceLayout::Full {
+            shader_defs.push("FULL_INSTANCE_TRANSFORM".into());
+        }
+        if key.alpha_mask {
+            shader_defs.push("CHUNK_ALPHA_MASK".into());
+        }
+        if key.billboard {
+            shader_defs.push("BILLBOARD".into());
+        }
+        //Same sway as the main pass, otherwise the shadows stand still
+        if key.sway_vertex_colors && layout.contains(Mesh::ATTRIBUTE_COLOR) {
+            shader_defs.push("SWAY_VERTEX_COLORS".into());
+            vertex_attributes.push(Mesh::ATTRIBUTE_COLOR.at_shader_location(4));
+        }
+
+        let fragment_required = key
+            .mesh_key
+            .intersects(MeshPipelineKey::MAY_DISCARD | MeshPipelineKey::DEPTH_CLAMP_ORTHO);
+        let fragment = fragment_required.then(|| FragmentState {
+            shader: self.shader.clone(),
+            entry_point: "fragment".into(),
+            shader_defs: shader_defs.clone(),
+            targets: vec![],
+        });
+
+        let mut descriptor = RenderPipelineDescriptor {
+            vertex: VertexState {
+                shader: self.shader.clone(),
+                entry_point: "vertex".into(),
+                shader_defs,
+                buffers: vec![
+                    layout.get_layout(&vertex_attributes)?,
+                    VertexBufferLayout {
+                        array_stride: (key.instance_format.stride() * std::mem::size_of::<f32>())
+                            as u64,
+                        step_mode: VertexStepMode::Instance,
+                        attributes: key.instance_format.vertex_attributes(),
+                    },
+                ],
+            },
+            fragment,
+            layout: vec![
+                self.view_layout.clone(),
+                self.material_pipeline.material_layout.clone(),
+                self.material_pipeline
+                    .mesh_pipeline
+                    .mesh_layouts
+                    .model_only
+                    .clone(),
+                self.chunk_instancing_bind_group_layout.clone(),
+            ],
+            primitive: PrimitiveState {
+                topology: key.mesh_key.primitive_topology(),
+                strip_index_format: None,
+                front_face: FrontFace::Ccw,
+                cull_mode: None,
+                unclipped_depth: false,
+                polygon_mode: PolygonMode::Fill,
+                conservative: false,
+            },
+            depth_stencil: Some(DepthStencilState {
+                format: SHADOW_FORMAT,
+                depth_write_enabled: true,
+                depth_compare: CompareFunction::GreaterEqual,
+                stencil: StencilState::default(),
+                bias: DepthBiasState::default(),
+            }),
+            multisample: MultisampleState::default(),
+            push_constant_ranges: Vec::new(),
+            label: Some("chunk_instancing_shadow_pipeline".into()),
+        };
+
+        //Cull mode and depth bias from the material
+        StandardMaterial::specialize(
+            &self.material_pipeline,
+            &mut descriptor,
+            layout,
+            MaterialPipelineKey {
+                mesh_key: key.mesh_key,
+                bind_group_data: key.material_key,
+            },
+        )?;
 
         Ok(descriptor)
     }
@@ -519,34 +2101,52 @@ impl SpecializedMeshPipeline for CustomPipeline {
 type DrawCustom = (
     SetItemPipeline,
     SetMeshViewBindGroup<0>,
-    // SetMaterialBindGroup<StandardMaterial, 1>,// Have not yet gotten the material to work. Still using own fragment shader :(
-    SetMeshBindGroup<1>,
+    SetChunkInstancingMaterialBindGroup<1>,
     SetMeshBindGroup<2>,
     SetChunkInstancingBindGroup<3>,
-    SetTextureBindGroup<4>,
     DrawMeshInstanced,
 );
 
-pub struct SetTextureBindGroup<const I: usize>;
-impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetTextureBindGroup<I> {
-    type Param = SQuery<Read<TextureBindGroup>>;
-    type ItemWorldQuery = ();
+//All chunks of a ChunkBatch in one go, queued for the first visible chunk of the batch
+type DrawCustomBatch = (
+    SetItemPipeline,
+    SetMeshViewBindGroup<0>,
+    SetChunkInstancingMaterialBindGroup<1>,
+    SetMeshBindGroup<2>,
+    SetChunkBatchBindGroup<3>,
+    DrawChunkBatch,
+);
+
+type DrawCustomShadow = (
+    SetItemPipeline,
+    SetPrepassViewBindGroup<0>,
+    SetChunkInstancingMaterialBindGroup<1>,
+    SetMeshBindGroup<2>,
+    SetChunkInstancingBindGroup<3>,
+    DrawMeshInstanced,
+);
+
+pub struct SetChunkInstancingMaterialBindGroup<const I: usize>;
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetChunkInstancingMaterialBindGroup<I> {
+    type Param = SRes<RenderMaterials<StandardMaterial>>;
+    type ItemWorldQuery = Read<ChunkInstancingMaterial>;
     type ViewWorldQuery = ();
 
     #[inline]
     fn render<'w>(
-        item: &P,
+        _item: &P,
         _view: ROQueryItem<'w, Self::ViewWorldQuery>,
-        _: ROQueryItem<'w, Self::ItemWorldQuery>,
-        bind_group_query: SystemParamItem<'w, '_, Self::Param>,
+        material_handle: ROQueryItem<'w, Self::ItemWorldQuery>,
+        materials: SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
-        if let Ok(bind_group) = bind_group_query.get_inner(item.entity()) {
-            pass.set_bind_group(I, &bind_group.0, &[]);
-            // return RenderCommandResult::Success;
+        match materials.into_inner().get(&material_handle.handle) {
+            Some(material) => {
+                pass.set_bind_group(I, &material.bind_group, &[]);
+                RenderCommandResult::Success
+            }
+            None => RenderCommandResult::Failure,
         }
-
-        RenderCommandResult::Success
     }
 }
 
@@ -573,13 +2173,41 @@ impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetChunkInstancingBindGr
     }
 }
 
+pub struct SetChunkBatchBindGroup<const I: usize>;
+impl<P: PhaseItem, const I: usize> RenderCommand<P> for SetChunkBatchBindGroup<I> {
+    type Param = SRes<ChunkBatches>;
+    type ItemWorldQuery = ();
+    type ViewWorldQuery = ();
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        _view: ROQueryItem<'w, Self::ViewWorldQuery>,
+        _: ROQueryItem<'w, Self::ItemWorldQuery>,
+        chunk_batches: SystemParamItem<'w, '_, Self::Param>,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let bind_group = chunk_batches
+            .into_inner()
+            .get(&item.entity())
+            .and_then(|batch| batch.bind_group.as_ref());
+        match bind_group {
+            Some(bind_group) => {
+                pass.set_bind_group(I, bind_group, &[]);
+                RenderCommandResult::Success
+            }
+            None => RenderCommandResult::Failure,
+        }
+    }
+}
+
 pub struct DrawMeshInstanced;
 
 impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
     type Param = (
         SRes<RenderAssets<Mesh>>,
         SQuery<Read<Handle<Mesh>>>,
-        SQuery<Read<ChunkInstancingInstanceBuffer>>,
+        SRes<ChunkInstancingInstanceBuffers>,
     );
     type ItemWorldQuery = ();
     type ViewWorldQuery = ();
@@ -589,17 +2217,15 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
         item: &P,
         _view: ROQueryItem<'w, Self::ViewWorldQuery>,
         _: ROQueryItem<'w, Self::ItemWorldQuery>,
-        (meshes, mesh_query, chunk_instancing_instance_buffer_query): SystemParamItem<
-            'w,
-            '_,
-            Self::Param,
-        >,
+        (meshes, mesh_query, instance_buffers): SystemParamItem<'w, '_, Self::Param>,
         pass: &mut TrackedRenderPass<'w>,
     ) -> RenderCommandResult {
         let mesh_handle = mesh_query.get(item.entity()).unwrap();
-        let instance_buffer = chunk_instancing_instance_buffer_query
-            .get_inner(item.entity())
-            .unwrap();
+        let instance_buffer = match instance_buffers.into_inner().buffers.get(&item.entity()) {
+            Some(instance_buffer) if instance_buffer.length > 0 => instance_buffer,
+            Some(_) => return RenderCommandResult::Success, //Nothing to draw
+            None => return RenderCommandResult::Failure,
+        };
 
         let gpu_mesh = match meshes.into_inner().get(mesh_handle) {
             Some(gpu_mesh) => gpu_mesh,
@@ -608,7 +2234,6 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
 
         pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
         pass.set_vertex_buffer(1, instance_buffer.buffer.slice(..));
-
         match &gpu_mesh.buffer_info {
             GpuBufferInfo::Indexed {
                 buffer,
@@ -625,3 +2250,86 @@ impl<P: PhaseItem> RenderCommand<P> for DrawMeshInstanced {
         RenderCommandResult::Success
     }
 }
+
+pub struct DrawChunkBatch;
+
+impl<P: PhaseItem> RenderCommand<P> for DrawChunkBatch {
+    type Param = (
+        SRes<RenderAssets<Mesh>>,
+        SQuery<Read<Handle<Mesh>>>,
+        SRes<ChunkBatches>,
+        Option<SRes<CulledInstanceBuffers>>,
+    );
+    type ItemWorldQuery = ();
+    type ViewWorldQuery = Entity;
+
+    #[inline]
+    fn render<'w>(
+        item: &P,
+        view: ROQueryItem<'w, Self::ViewWorldQuery>,
+        _: ROQueryItem<'w, Self::ItemWorldQuery>,
+        (meshes, mesh_query, chunk_batches, culled_instance_buffers): SystemParamItem<
+            'w,
+            '_,
+            Self::Param,
+        >,
+        pass: &mut TrackedRenderPass<'w>,
+    ) -> RenderCommandResult {
+        let mesh_handle = mesh_query.get(item.entity()).unwrap();
+        let Some(batch) = chunk_batches.into_inner().get(&item.entity()) else {
+            return RenderCommandResult::Failure;
+        };
+        let Some(gpu_mesh) = meshes.into_inner().get(mesh_handle) else {
+            return RenderCommandResult::Failure;
+        };
+
+        //Only the instances that survived the gpu culling for this view, the instance counts are written by the compute shader
+        let culled_instances = culled_instance_buffers
+            .and_then(|culled| culled.into_inner().0.get(&(view, batch.id)))
+            .filter(|culled| culled.ready);
+        let (instance_buffer, indirect_buffer) = match culled_instances {
+            Some(culled) => (&culled.visible_buffer, &culled.indirect_buffer),
+            None => (&batch.instance_buffer, &batch.indirect_buffer),
+        };
+
+        pass.set_vertex_buffer(0, gpu_mesh.vertex_buffer.slice(..));
+        let indexed = match &gpu_mesh.buffer_info {
+            GpuBufferInfo::Indexed {
+                buffer,
+                index_format,
+                ..
+            } => {
+                pass.set_index_buffer(buffer.slice(..), 0, *index_format);
+                true
+            }
+            GpuBufferInfo::NonIndexed => false,
+        };
+
+        if batch.multi_draw {
+            pass.set_vertex_buffer(1, instance_buffer.slice(..));
+            let draw_count = batch.draws.len() as u32;
+            if indexed {
+                pass.multi_draw_indexed_indirect(indirect_buffer, 0, draw_count);
+            } else {
+                pass.multi_draw_indirect(indirect_buffer, 0, draw_count);
+            }
+        } else {
+            //Without indirect first_instance the instance buffer is offset to each chunk instead
+            let instance_size = (batch.stride * std::mem::size_of::<f32>()) as u64;
+            let args_size = (batch.args_len() * std::mem::size_of::<u32>()) as u64;
+            for (i, draw) in batch.draws.iter().enumerate() {
+                if !draw.visible {
+                    continue;
+                }
+                let instances_start = draw.first_instance as u64 * instance_size;
+                pass.set_vertex_buffer(1, instance_buffer.slice(instances_start..));
+                if indexed {
+                    pass.draw_indexed_indirect(indirect_buffer, i as u64 * args_size);
+                } else {
+                    pass.draw_indirect(indirect_buffer, i as u64 * args_size);
+                }
+            }
+        }
+        RenderCommandResult::Success
+    }
+}
@@ -0,0 +1,1263 @@
+use bevy::{
+    asset::HandleId,
+    core_pipeline::core_3d::{self, Camera3d, Opaque3d},
+    ecs::query::QueryItem,
+    pbr::MeshUniform,
+    prelude::*,
+    render::{
+        mesh::GpuBufferInfo,
+        primitives::{Aabb, Frustum},
+        render_asset::RenderAssets,
+        render_graph::{
+            NodeRunError, RenderGraphApp, RenderGraphContext, ViewNode, ViewNodeRunner,
+        },
+        render_phase::RenderPhase,
+        render_resource::*,
+        renderer::{RenderContext, RenderDevice, RenderQueue},
+        view::{ComputedVisibility, ExtractedView, ViewDepthTexture},
+        Extract, Render, RenderApp, RenderSet,
+    },
+    utils::{HashMap, HashSet},
+};
+use bytemuck::{Pod, Zeroable};
+
+use super::{
+    chunk_grass::{ChunkGrass, GrassDensityLod, GridConfig},
+    chunk_instancing::{
+        prepare_chunk_batches, ChunkBatches, ChunkInstancing, ChunkLod, InstanceFormat,
+        InstanceLayout,
+    },
+    wind::{Wind, WindZones},
+};
+
+//Culls every instance of the batched instanced chunks against the camera frustum in a compute pass and only draws the visible ones.
+//Instances and grass chunks hidden behind what was drawn last frame are culled too, by testing their bounds against a depth pyramid
+//built from the previous frame's depth. Things uncovered by fast camera movement can show up one frame late.
+//Needs compute shaders, so don't add it when targeting webgl2. Shadows still draw all instances of a chunk.
+pub struct GpuInstanceCullingPlugin;
+
+pub const INSTANCE_CULLING: &str = "chunk_instance_culling";
+pub const DEPTH_PYRAMID: &str = "chunk_depth_pyramid";
+
+impl Plugin for GpuInstanceCullingPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_systems(Update, enable_depth_texture_binding);
+
+        let render_app = match app.get_sub_app_mut(RenderApp) {
+            Ok(render_app) => render_app,
+            Err(_) => return,
+        };
+
+        render_app
+            .init_resource::<SpecializedComputePipelines<InstanceCullingPipeline>>()
+            .init_resource::<CulledInstanceBuffers>()
+            .init_resource::<OccludedGrassBuffers>()
+            .init_resource::<DepthPyramids>()
+            .add_systems(ExtractSchedule, extract_instance_culling_bounds)
+            .add_systems(
+                Render,
+                (
+                    prepare_culled_instance_buffers
+                        .in_set(RenderSet::Prepare)
+                        .after(prepare_chunk_batches),
+                    prepare_occluded_grass_buffers.in_set(RenderSet::Prepare),
+                    //Needs the ViewDepthTexture, which is only inserted at the end of Prepare
+                    queue_depth_pyramids.in_set(RenderSet::Queue),
+                ),
+            )
+            .add_render_graph_node::<ViewNodeRunner<InstanceCullingNode>>(
+                core_3d::graph::NAME,
+                INSTANCE_CULLING,
+            )
+            .add_render_graph_node::<ViewNodeRunner<DepthPyramidNode>>(
+                core_3d::graph::NAME,
+                DEPTH_PYRAMID,
+            )
+            .add_render_graph_edges(
+                core_3d::graph::NAME,
+                &[INSTANCE_CULLING, core_3d::graph::node::START_MAIN_PASS],
+            )
+            .add_render_graph_edges(
+                core_3d::graph::NAME,
+                &[INSTANCE_CULLING, core_3d::graph::node::PREPASS],
+            )
+            .add_render_graph_edges(
+                core_3d::graph::NAME,
+                &[
+                    core_3d::graph::node::MAIN_OPAQUE_PASS,
+                    DEPTH_PYRAMID,
+                    core_3d::graph::node::MAIN_TRANSPARENT_PASS,
+                ],
+            );
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = match app.get_sub_app_mut(RenderApp) {
+            Ok(render_app) => render_app,
+            Err(_) => return,
+        };
+
+        //The culling pipelines use the occlusion layout of the depth pyramid pipeline
+        render_app
+            .init_resource::<DepthPyramidPipeline>()
+            .init_resource::<InstanceCullingPipeline>()
+            .init_resource::<GrassCullingPipeline>();
+    }
+}
+
+//The depth pyramid is built from the depth texture, which can't be read in a shader by default
+fn enable_depth_texture_binding(mut cameras: Query<&mut Camera3d, Added<Camera3d>>) {
+    for mut camera_3d in &mut cameras {
+        let usages = TextureUsages::from(camera_3d.depth_texture_usages);
+        camera_3d.depth_texture_usages = (usages | TextureUsages::TEXTURE_BINDING).into();
+    }
+}
+
+// ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+// █░░░░░░░░░░░░░░█░░░░░░░░██░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░░░███░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█
+// █░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀░░██░░▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░▄▀░░░░░░░░░░█░░░░▄▀░░██░░▄▀░░░░█░░░░░░▄▀░░░░░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░░░█░░░░░░▄▀░░░░░░█
+// █░░▄▀░░███████████░░▄▀▄▀░░▄▀▄▀░░███████░░▄▀░░█████░░▄▀░░████░░▄▀░░███░░▄▀░░██░░▄▀░░█░░▄▀░░█████████████░░▄▀░░█████
+// █░░▄▀░░░░░░░░░░███░░░░▄▀▄▀▄▀░░░░███████░░▄▀░░█████░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░█████████████░░▄▀░░█████
+// █░░▄▀▄▀▄▀▄▀▄▀░░█████░░▄▀▄▀▄▀░░█████████░░▄▀░░█████░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░█████████████░░▄▀░░█████
+// █░░▄▀░░░░░░░░░░███░░░░▄▀▄▀▄▀░░░░███████░░▄▀░░█████░░▄▀░░░░░░▄▀░░░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░█████████████░░▄▀░░█████
+// █░░▄▀░░███████████░░▄▀▄▀░░▄▀▄▀░░███████░░▄▀░░█████░░▄▀░░██░░▄▀░░█████░░▄▀░░██░░▄▀░░█░░▄▀░░█████████████░░▄▀░░█████
+// █░░▄▀░░░░░░░░░░█░░░░▄▀░░██░░▄▀░░░░█████░░▄▀░░█████░░▄▀░░██░░▄▀░░░░░░█░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█████░░▄▀░░█████
+// █░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀░░██░░▄▀▄▀░░█████░░▄▀░░█████░░▄▀░░██░░▄▀▄▀▄▀░░█░░▄▀░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█████░░▄▀░░█████
+// █░░░░░░░░░░░░░░█░░░░░░░░██░░░░░░░░█████░░░░░░█████░░░░░░██░░░░░░░░░░█░░░░░░██░░░░░░█░░░░░░░░░░░░░░█████░░░░░░█████
+// ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+
+#[derive(Component, Clone, Copy)]
+pub struct InstanceCullingBounds {
+    sphere: Vec4, //Bounding sphere of the chunk mesh in the space the instances are placed in, [x,y,z, radius]
+    chunk_min: Vec3, //Bounds of all instances in the space of the chunk
+    chunk_max: Vec3,
+}
+
+//Where the instances of a chunk are placed, [min, max, largest instance scale]
+type InstancePlacement = (Vec3, Vec3, f32);
+
+fn instance_placement(chunk_instancing: &ChunkInstancing) -> InstancePlacement {
+    let mut min = Vec3::splat(f32::MAX);
+    let mut max = Vec3::splat(f32::MIN);
+    let mut max_scale = 0.0_f32;
+    for instance in chunk_instancing.instances.iter() {
+        let position = Vec3::from_slice(&instance.pos_xyz);
+        min = min.min(position);
+        max = max.max(position);
+        let scale = match chunk_instancing.instance_layout {
+            InstanceLayout::Full => instance.pos_xyz[3] * instance.scale.abs().max_element(),
+            InstanceLayout::Compact => instance.pos_xyz[3] * 1.1, //Largest random_scale in the shader
+        };
+        max_scale = max_scale.max(scale.abs());
+    }
+    (min, max, max_scale)
+}
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn extract_instance_culling_bounds(
+    mut commands: Commands,
+    mut previous_len: Local<usize>,
+    mut mesh_bounds: Local<HashMap<HandleId, Aabb>>,
+    mut placements: Local<HashMap<Entity, InstancePlacement>>,
+    mut mesh_events: Extract<EventReader<AssetEvent<Mesh>>>,
+    meshes: Extract<Res<Assets<Mesh>>>,
+    wind: Extract<Res<Wind>>,
+    wind_zones: Extract<Res<WindZones>>,
+    query: Extract<
+        Query<(
+            Entity,
+            &ComputedVisibility,
+            Ref<ChunkInstancing>,
+            &Handle<Mesh>,
+            Option<&ChunkLod>,
+        )>,
+    >,
+) {
+    //Meshes are only read back when they change, going through all vertices every frame is slow
+    for event in mesh_events.iter() {
+        match event {
+            AssetEvent::Modified { handle } | AssetEvent::Removed { handle } => {
+                mesh_bounds.remove(&handle.id());
+            }
+            AssetEvent::Created { .. } => {}
+        }
+    }
+
+    //Same for the instance positions, they only change with the ChunkInstancing
+    placements.retain(|entity, _| query.contains(*entity));
+
+    let mut values = Vec::with_capacity(*previous_len);
+    for (entity, computed_visibility, chunk_instancing, mesh_handle, chunk_lod) in &query {
+        if chunk_instancing.is_changed() {
+            placements.remove(&entity);
+        }
+        if !computed_visibility.is_visible() {
+            continue;
+        }
+        let aabb = match mesh_bounds.get(&mesh_handle.id()) {
+            Some(aabb) => *aabb,
+            None => {
+                let Some(aabb) = meshes.get(mesh_handle).and_then(|mesh| mesh.compute_aabb())
+                else {
+                    continue;
+                };
+                mesh_bounds.insert(mesh_handle.id(), aabb);
+                aabb
+            }
+        };
+
+        let mut center = Vec3::from(aabb.center);
+        let mut radius = aabb.half_extents.length();
+        //Billboards don't use the model_transform
+        if chunk_lod
+            .and_then(|chunk_lod| chunk_lod.active_impostor())
+            .is_none()
+        {
+            let model_transform = chunk_instancing.model_transform;
+            center = model_transform.transform_point(center);
+            radius *= model_transform.scale.abs().max_element();
+
+            //Wind sway leans the tops up to about a quarter of their height, wind zones up to half (see wind_sway in the shader)
+            let flexibility = 1.0 - chunk_instancing.stiffness.clamp(0.0, 1.0);
+            let sway = (wind.strength.abs() * (0.25 * (1.0 + wind.gustiness.abs()) + 0.1)
+                + wind_zones.max_strength() * 0.5)
+                * flexibility;
+            radius += (center.z.abs() + radius) * sway;
+        }
+
+        let (min, max, max_scale) = *placements
+            .entry(entity)
+            .or_insert_with(|| instance_placement(&chunk_instancing));
+        //Instances can be rotated around their position, so pad with the whole sphere
+        let padding = (center.length() + radius) * max_scale;
+        values.push((
+            entity,
+            InstanceCullingBounds {
+                sphere: center.extend(radius),
+                chunk_min: min - padding,
+                chunk_max: max + padding,
+            },
+        ));
+    }
+    *previous_len = values.len();
+    commands.insert_or_spawn_batch(values);
+}
+
+// ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+// █░░░░░░░░░░░░░░█░░░░░░░░░░░░░░░░███░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░░░███░░░░░░░░░░░░░░█
+// █░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░░░░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░░░░░█
+// █░░▄▀░░██░░▄▀░░█░░▄▀░░████░░▄▀░░███░░▄▀░░█████████░░▄▀░░██░░▄▀░░█░░▄▀░░██░░▄▀░░█░░▄▀░░████░░▄▀░░███░░▄▀░░█████████
+// █░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░░░░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░░░░░█
+// █░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░▄▀░░░░░░░░░░█░░▄▀░░░░░░▄▀░░░░███░░▄▀░░░░░░░░░░█░░▄▀░░░░░░░░░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░▄▀░░░░███░░▄▀░░░░░░░░░░█
+// █░░▄▀░░█████████░░▄▀░░██░░▄▀░░█████░░▄▀░░█████████░░▄▀░░█████████░░▄▀░░██░░▄▀░░█░░▄▀░░██░░▄▀░░█████░░▄▀░░█████████
+// █░░▄▀░░█████████░░▄▀░░██░░▄▀░░░░░░█░░▄▀░░░░░░░░░░█░░▄▀░░█████████░░▄▀░░██░░▄▀░░█░░▄▀░░██░░▄▀░░░░░░█░░▄▀░░░░░░░░░░█
+// █░░▄▀░░█████████░░▄▀░░██░░▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░█████████░░▄▀░░██░░▄▀░░█░░▄▀░░██░░▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░░░░░█████████░░░░░░██░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░█████████░░░░░░██░░░░░░█░░░░░░██░░░░░░░░░░█░░░░░░░░░░░░░░█
+// ██████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct GpuCullingView {
+    planes: [[f32; 4]; 5], //left, right, bottom, top, near
+    counts: [u32; 4],      //[nr instances in the batch, floats per instance, padding...]
+}
+
+//One per slot of the batch, the instances find theirs with the slot index they carry
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct GpuChunkCullingData {
+    chunk_transform: [[f32; 4]; 4],
+    bounding_sphere: [f32; 4],
+    chunk_scale: [f32; 4], //[largest scale axis of the chunk transform, padding...]
+    counts: [u32; 4], //[nr instances, padding, first instance in the batch, index of the instance count in the indirect args]. No instances for hidden chunks
+    chunk_min: [f32; 4], //World space bounds of all instances, for the occlusion test of the whole chunk
+    chunk_max: [f32; 4],
+}
+
+//World space bounds of a box after transforming it
+fn transform_bounds(transform: Mat4, min: Vec3, max: Vec3) -> (Vec3, Vec3) {
+    let mut world_min = Vec3::splat(f32::MAX);
+    let mut world_max = Vec3::splat(f32::MIN);
+    for i in 0..8 {
+        let corner = Vec3::select(BVec3::new(i & 1 != 0, i & 2 != 0, i & 4 != 0), max, min);
+        let world_corner = transform.transform_point3(corner);
+        world_min = world_min.min(world_corner);
+        world_max = world_max.max(world_corner);
+    }
+    (world_min, world_max)
+}
+
+//Visible instances of one chunk batch as seen from one view, packed per chunk at the same offsets as in the batch
+pub struct CulledInstances {
+    pub(crate) visible_buffer: Buffer,
+    pub(crate) indirect_buffer: Buffer,
+    pub(crate) ready: bool, //False until the compute pipeline has compiled, the full batch is drawn until then
+    capacity: usize,        //Nr of floats that fit in the visible buffer
+    draw_capacity: usize, //Nr of draws that fit in the indirect buffer, also the nr of chunks that fit in the chunk buffer
+    pipeline: CachedComputePipelineId,
+    view_buffer: Buffer,
+    chunk_buffer: Buffer,
+    bind_group: Option<BindGroup>, //Recreated when one of the buffers is
+    instance_buffer_id: Option<BufferId>, //Instance buffer of the batch the bind group was made with
+    nr_instances: u32,                    //Instances the culling runs for
+}
+
+impl CulledInstances {
+    fn new(
+        render_device: &RenderDevice,
+        capacity: usize,
+        draw_capacity: usize,
+        pipeline: CachedComputePipelineId,
+    ) -> Self {
+        let view_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("instance culling view buffer"),
+            size: std::mem::size_of::<GpuCullingView>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let chunk_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("instance culling chunk buffer"),
+            size: (draw_capacity.max(1) * std::mem::size_of::<GpuChunkCullingData>()) as u64,
+            usage: BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let visible_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("visible instance buffer"),
+            size: (capacity * std::mem::size_of::<f32>()) as u64,
+            usage: BufferUsages::VERTEX | BufferUsages::STORAGE,
+            mapped_at_creation: false,
+        });
+        let indirect_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("culled instances indirect buffer"),
+            size: (draw_capacity * 5 * std::mem::size_of::<u32>()) as u64,
+            usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        Self {
+            visible_buffer,
+            indirect_buffer,
+            ready: false,
+            capacity,
+            draw_capacity,
+            pipeline,
+            view_buffer,
+            chunk_buffer,
+            bind_group: None,
+            instance_buffer_id: None,
+            nr_instances: 0,
+        }
+    }
+}
+
+//Keyed by (view entity, id of the batch), lives across frames
+#[derive(Resource, Default)]
+pub struct CulledInstanceBuffers(pub(crate) HashMap<(Entity, u64), CulledInstances>);
+
+#[allow(clippy::too_many_arguments)]
+fn prepare_culled_instance_buffers(
+    mut culled_instance_buffers: ResMut<CulledInstanceBuffers>,
+    culling_pipeline: Res<InstanceCullingPipeline>,
+    mut pipelines: ResMut<SpecializedComputePipelines<InstanceCullingPipeline>>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    chunk_batches: Res<ChunkBatches>,
+    depth_pyramids: Res<DepthPyramids>,
+    views: Query<(Entity, &ExtractedView), With<RenderPhase<Opaque3d>>>,
+    chunks: Query<(&MeshUniform, &InstanceCullingBounds)>,
+) {
+    let mut in_use = HashSet::new();
+    for (view_entity, view) in &views {
+        let view_projection = view.projection * view.transform.compute_matrix().inverse();
+        let frustum = Frustum::from_view_projection(&view_projection);
+        let mut planes = [[0.0; 4]; 5];
+        for (plane, half_space) in planes.iter_mut().zip(frustum.half_spaces.iter()) {
+            *plane = half_space.normal_d().to_array();
+        }
+
+        //The occlusion bind group of a view exists from its second frame on
+        let has_pyramid = depth_pyramids.0.contains_key(&view_entity);
+
+        for (_, batch) in chunk_batches.iter_queued() {
+            let pipeline =
+                pipelines.specialize(&pipeline_cache, &culling_pipeline, batch.instance_format());
+            let key = (view_entity, batch.id);
+            in_use.insert(key);
+            let culled_instances = culled_instance_buffers.0.entry(key).or_insert_with(|| {
+                CulledInstances::new(&render_device, batch.capacity, batch.draws.len(), pipeline)
+            });
+            if culled_instances.capacity < batch.capacity
+                || culled_instances.draw_capacity < batch.draws.len()
+            {
+                *culled_instances = CulledInstances::new(
+                    &render_device,
+                    batch.capacity,
+                    batch.draws.len().max(culled_instances.draw_capacity * 2),
+                    pipeline,
+                );
+            }
+            culled_instances.pipeline = pipeline;
+            culled_instances.ready =
+                has_pyramid && pipeline_cache.get_compute_pipeline(pipeline).is_some();
+            if !culled_instances.ready {
+                continue;
+            }
+
+            let mut chunk_data = vec![GpuChunkCullingData::zeroed(); batch.draws.len()];
+            for (i, (chunk_entity, draw)) in batch.chunks.iter().zip(&batch.draws).enumerate() {
+                //Hidden chunks keep no instances
+                let (Some(chunk_entity), true) = (chunk_entity, draw.visible) else {
+                    continue;
+                };
+                //Bounds are extracted from the main world, a chunk without them can't be culled
+                let Ok((mesh_uniform, bounds)) = chunks.get(*chunk_entity) else {
+                    culled_instances.ready = false;
+                    break;
+                };
+                let chunk_transform = mesh_uniform.transform;
+                let chunk_scale = chunk_transform
+                    .x_axis
+                    .truncate()
+                    .length()
+                    .max(chunk_transform.y_axis.truncate().length())
+                    .max(chunk_transform.z_axis.truncate().length());
+                let (chunk_min, chunk_max) =
+                    transform_bounds(chunk_transform, bounds.chunk_min, bounds.chunk_max);
+                chunk_data[i] = GpuChunkCullingData {
+                    chunk_transform: chunk_transform.to_cols_array_2d(),
+                    bounding_sphere: bounds.sphere.to_array(),
+                    chunk_scale: [chunk_scale, 0.0, 0.0, 0.0],
+                    counts: [
+                        draw.nr_instances,
+                        0,
+                        draw.first_instance,
+                        batch.instance_count_index(i),
+                    ],
+                    chunk_min: chunk_min.extend(0.0).to_array(),
+                    chunk_max: chunk_max.extend(0.0).to_array(),
+                };
+            }
+            if !culled_instances.ready {
+                continue;
+            }
+
+            //The compute shader counts the visible instances up from 0 again every frame
+            render_queue.write_buffer(
+                &culled_instances.indirect_buffer,
+                0,
+                bytemuck::cast_slice(&batch.draw_args(false)),
+            );
+            let view_data = GpuCullingView {
+                planes,
+                counts: [batch.nr_instances, batch.stride as u32, 0, 0],
+            };
+            render_queue.write_buffer(
+                &culled_instances.view_buffer,
+                0,
+                bytemuck::bytes_of(&view_data),
+            );
+            render_queue.write_buffer(
+                &culled_instances.chunk_buffer,
+                0,
+                bytemuck::cast_slice(&chunk_data),
+            );
+            culled_instances.nr_instances = batch.nr_instances;
+
+            if culled_instances.instance_buffer_id != Some(batch.instance_buffer.id()) {
+                culled_instances.bind_group = None;
+            }
+            if culled_instances.bind_group.is_none() {
+                culled_instances.bind_group =
+                    Some(render_device.create_bind_group(&BindGroupDescriptor {
+                        label: Some("instance_culling_bind_group"),
+                        layout: &culling_pipeline.layout,
+                        entries: &[
+                            BindGroupEntry {
+                                binding: 0,
+                                resource: culled_instances.view_buffer.as_entire_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 1,
+                                resource: batch.instance_buffer.as_entire_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 2,
+                                resource: culled_instances.visible_buffer.as_entire_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 3,
+                                resource: culled_instances.indirect_buffer.as_entire_binding(),
+                            },
+                            BindGroupEntry {
+                                binding: 4,
+                                resource: culled_instances.chunk_buffer.as_entire_binding(),
+                            },
+                        ],
+                    }));
+                culled_instances.instance_buffer_id = Some(batch.instance_buffer.id());
+            }
+        }
+    }
+    culled_instance_buffers
+        .0
+        .retain(|key, _| in_use.contains(key));
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct GpuGrassCullingData {
+    chunk_min: [f32; 4], //World space bounds of the chunk including the grass height
+    chunk_max: [f32; 4],
+}
+
+//Indirect draw of one grass chunk as seen from one view, the instance count is set to 0 when the chunk is occluded
+pub struct OccludedGrass {
+    pub(crate) indirect_buffer: Buffer,
+    pub(crate) ready: bool, //False until the compute pipeline has compiled, drawn as usual until then
+    culling_buffer: Buffer,
+    bind_group: BindGroup,
+}
+
+//Keyed by (view entity, chunk entity), lives across frames
+#[derive(Resource, Default)]
+pub struct OccludedGrassBuffers(pub(crate) HashMap<(Entity, Entity), OccludedGrass>);
+
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+fn prepare_occluded_grass_buffers(
+    mut occluded_grass_buffers: ResMut<OccludedGrassBuffers>,
+    grass_culling_pipeline: Res<GrassCullingPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    meshes: Res<RenderAssets<Mesh>>,
+    depth_pyramids: Res<DepthPyramids>,
+    grid_config: Res<GridConfig>,
+    views: Query<(Entity, &ExtractedView), With<RenderPhase<Opaque3d>>>,
+    chunks: Query<(
+        Entity,
+        &MeshUniform,
+        &Handle<Mesh>,
+        &ChunkGrass,
+        Option<&GrassDensityLod>,
+    )>,
+) {
+    //Grass on a height map can sit anywhere in the height range
+    let (min_terrain, max_terrain) = match grid_config.height_map {
+        Some(_) => (
+            grid_config.min_height.min(grid_config.max_height).min(0.0),
+            grid_config.min_height.max(grid_config.max_height).max(0.0),
+        ),
+        None => (0.0, 0.0),
+    };
+
+    let pipeline_ready = pipeline_cache
+        .get_compute_pipeline(grass_culling_pipeline.pipeline)
+        .is_some();
+
+    let mut in_use = HashSet::new();
+    for (view_entity, view) in &views {
+        let has_pyramid = depth_pyramids.0.contains_key(&view_entity);
+
+        for (entity, mesh_uniform, mesh_handle, chunk_grass, density_lod) in &chunks {
+            let Some(gpu_mesh) = meshes.get(mesh_handle) else {
+                continue;
+            };
+            let GpuBufferInfo::Indexed { count, .. } = &gpu_mesh.buffer_info else {
+                continue;
+            };
+
+            let key = (view_entity, entity);
+            in_use.insert(key);
+            let occluded_grass = occluded_grass_buffers.0.entry(key).or_insert_with(|| {
+                let indirect_buffer = render_device.create_buffer(&BufferDescriptor {
+                    label: Some("occluded grass indirect buffer"),
+                    size: (5 * std::mem::size_of::<u32>()) as u64,
+                    usage: BufferUsages::INDIRECT | BufferUsages::STORAGE | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                let culling_buffer = render_device.create_buffer(&BufferDescriptor {
+                    label: Some("grass culling buffer"),
+                    size: std::mem::size_of::<GpuGrassCullingData>() as u64,
+                    usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+                    mapped_at_creation: false,
+                });
+                //Both buffers live as long as the (view, chunk) pair so the bind group does too
+                let bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("grass_culling_bind_group"),
+                    layout: &grass_culling_pipeline.layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: culling_buffer.as_entire_binding(),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: indirect_buffer.as_entire_binding(),
+                        },
+                    ],
+                });
+                OccludedGrass {
+                    indirect_buffer,
+                    ready: false,
+                    culling_buffer,
+                    bind_group,
+                }
+            });
+            occluded_grass.ready = has_pyramid && pipeline_ready;
+            if !occluded_grass.ready {
+                continue;
+            }
+
+            //Draws everything the density lod leaves unless the compute shader finds the chunk occluded
+            let nr_instances = chunk_grass.lod_instance_count(
+                density_lod,
+                &mesh_uniform.transform,
+                view.transform.translation(),
+            );
+            let indirect_args: [u32; 5] = [*count, nr_instances, 0, 0, 0];
+            render_queue.write_buffer(
+                &occluded_grass.indirect_buffer,
+                0,
+                bytemuck::cast_slice(&indirect_args),
+            );
+
+            //Straws are spread over the chunk and reach up to scale*height_modifier, leaning and swaying sideways in the wind
+            //Far straws get wider with the density lod
+            let straw_height = chunk_grass.scale * chunk_grass.height_modifier;
+            let straw_width = 0.05 * chunk_grass.scale
+                / density_lod.map_or(1.0, |density_lod| density_lod.min_density.max(0.01));
+            let padding = straw_height + straw_width;
+            let chunk_extents = Vec2::from(chunk_grass.chunk_half_extents) * 2.0;
+            let (chunk_min, chunk_max) = transform_bounds(
+                mesh_uniform.transform,
+                Vec3::new(-padding, -padding, min_terrain),
+                chunk_extents.extend(straw_height * 1.1 + max_terrain)
+                    + Vec3::new(padding, padding, 0.0),
+            );
+            let culling_data = GpuGrassCullingData {
+                chunk_min: chunk_min.extend(0.0).to_array(),
+                chunk_max: chunk_max.extend(0.0).to_array(),
+            };
+            render_queue.write_buffer(
+                &occluded_grass.culling_buffer,
+                0,
+                bytemuck::bytes_of(&culling_data),
+            );
+        }
+    }
+    occluded_grass_buffers
+        .0
+        .retain(|key, _| in_use.contains(key));
+}
+
+#[derive(Clone, Copy, Pod, Zeroable)]
+#[repr(C)]
+struct GpuOcclusionData {
+    view_proj: [[f32; 4]; 4], //View projection the pyramid was built with
+    pyramid_size: [f32; 2],
+    mip_count: u32,
+    enabled: u32, //0 until the first pyramid has been built
+}
+
+//Farthest depth of the previous frame in a mip chain, one per view
+pub struct DepthPyramid {
+    _texture: Texture,
+    mip_views: Vec<TextureView>,
+    size: UVec2, //Size of the first level, the depth size rounded down to a power of two
+    depth_size: UVec2, //Size of the depth texture it was made for
+    view_proj: Mat4,
+    built: bool, //The pyramid holds the depth seen with view_proj
+    multisampled: bool,
+    occlusion_buffer: Buffer,
+    occlusion_bind_group: BindGroup,
+    first_level_bind_group: Option<BindGroup>, //Reads the depth texture, rebuilt when the view gets a new one
+    first_level_source: Option<TextureViewId>,
+    level_bind_groups: Vec<BindGroup>, //Mip 1 and up, each one reads the mip before it
+}
+
+impl DepthPyramid {
+    fn new(
+        render_device: &RenderDevice,
+        pyramid_pipeline: &DepthPyramidPipeline,
+        depth_size: UVec2,
+    ) -> Self {
+        let previous_power_of_two = |x: u32| 1 << (31 - x.max(1).leading_zeros());
+        let size = UVec2::new(
+            previous_power_of_two(depth_size.x),
+            previous_power_of_two(depth_size.y),
+        );
+        let mip_count = 32 - size.max_element().leading_zeros();
+        let texture = render_device.create_texture(&TextureDescriptor {
+            label: Some("depth_pyramid_texture"),
+            size: Extent3d {
+                width: size.x,
+                height: size.y,
+                depth_or_array_layers: 1,
+            },
+            mip_level_count: mip_count,
+            sample_count: 1,
+            dimension: TextureDimension::D2,
+            format: TextureFormat::R32Float,
+            usage: TextureUsages::STORAGE_BINDING | TextureUsages::TEXTURE_BINDING,
+            view_formats: &[],
+        });
+        let mip_views: Vec<TextureView> = (0..mip_count)
+            .map(|mip| {
+                texture.create_view(&TextureViewDescriptor {
+                    label: Some("depth_pyramid_mip_view"),
+                    base_mip_level: mip,
+                    mip_level_count: Some(1),
+                    ..default()
+                })
+            })
+            .collect();
+        let level_bind_groups = (1..mip_views.len())
+            .map(|mip| {
+                render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("depth_pyramid_downsample_bind_group"),
+                    layout: &pyramid_pipeline.level_layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(&mip_views[mip - 1]),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&mip_views[mip]),
+                        },
+                    ],
+                })
+            })
+            .collect();
+
+        let occlusion_buffer = render_device.create_buffer(&BufferDescriptor {
+            label: Some("occlusion buffer"),
+            size: std::mem::size_of::<GpuOcclusionData>() as u64,
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        let pyramid_view = texture.create_view(&TextureViewDescriptor {
+            label: Some("depth_pyramid_view"),
+            ..default()
+        });
+        let occlusion_bind_group = render_device.create_bind_group(&BindGroupDescriptor {
+            label: Some("occlusion_bind_group"),
+            layout: &pyramid_pipeline.occlusion_layout,
+            entries: &[
+                BindGroupEntry {
+                    binding: 0,
+                    resource: occlusion_buffer.as_entire_binding(),
+                },
+                BindGroupEntry {
+                    binding: 1,
+                    resource: BindingResource::TextureView(&pyramid_view),
+                },
+            ],
+        });
+
+        Self {
+            _texture: texture,
+            mip_views,
+            size,
+            depth_size,
+            view_proj: Mat4::IDENTITY,
+            built: false,
+            multisampled: false,
+            occlusion_buffer,
+            occlusion_bind_group,
+            first_level_bind_group: None,
+            first_level_source: None,
+            level_bind_groups,
+        }
+    }
+}
+
+//Keyed by view entity, lives across frames since the culling reads the pyramid of the previous frame
+#[derive(Resource, Default)]
+pub struct DepthPyramids(pub(crate) HashMap<Entity, DepthPyramid>);
+
+fn queue_depth_pyramids(
+    mut depth_pyramids: ResMut<DepthPyramids>,
+    pyramid_pipeline: Res<DepthPyramidPipeline>,
+    pipeline_cache: Res<PipelineCache>,
+    render_device: Res<RenderDevice>,
+    render_queue: Res<RenderQueue>,
+    views: Query<(Entity, &ExtractedView, &ViewDepthTexture), With<RenderPhase<Opaque3d>>>,
+) {
+    let mut in_use = HashSet::new();
+    for (view_entity, view, depth) in &views {
+        in_use.insert(view_entity);
+        let depth_size = UVec2::new(depth.texture.width(), depth.texture.height());
+        let depth_pyramid = depth_pyramids
+            .0
+            .entry(view_entity)
+            .or_insert_with(|| DepthPyramid::new(&render_device, &pyramid_pipeline, depth_size));
+        if depth_pyramid.depth_size != depth_size {
+            *depth_pyramid = DepthPyramid::new(&render_device, &pyramid_pipeline, depth_size);
+        }
+
+        //The culling runs before this frame's depth exists, so it tests against the pyramid from last frame
+        let occlusion_data = GpuOcclusionData {
+            view_proj: depth_pyramid.view_proj.to_cols_array_2d(),
+            pyramid_size: depth_pyramid.size.as_vec2().to_array(),
+            mip_count: depth_pyramid.mip_views.len() as u32,
+            enabled: depth_pyramid.built as u32,
+        };
+        render_queue.write_buffer(
+            &depth_pyramid.occlusion_buffer,
+            0,
+            bytemuck::bytes_of(&occlusion_data),
+        );
+
+        //Cameras need TEXTURE_BINDING on their depth, GpuInstanceCullingPlugin adds it to every Camera3d when it's spawned
+        let multisampled = depth.texture.sample_count() > 1;
+        let first_level_pipeline = match multisampled {
+            true => pyramid_pipeline.first_level_multisampled,
+            false => pyramid_pipeline.first_level,
+        };
+        depth_pyramid.built = depth
+            .texture
+            .usage()
+            .contains(TextureUsages::TEXTURE_BINDING)
+            && pipeline_cache
+                .get_compute_pipeline(first_level_pipeline)
+                .is_some()
+            && pipeline_cache
+                .get_compute_pipeline(pyramid_pipeline.level)
+                .is_some();
+        if !depth_pyramid.built {
+            continue;
+        }
+        depth_pyramid.multisampled = multisampled;
+        depth_pyramid.view_proj = view.projection * view.transform.compute_matrix().inverse();
+
+        //The rest of the chain only reads the pyramid itself, so only the first level follows the depth texture
+        if depth_pyramid.first_level_source != Some(depth.view.id()) {
+            let layout = match multisampled {
+                true => &pyramid_pipeline.first_level_multisampled_layout,
+                false => &pyramid_pipeline.first_level_layout,
+            };
+            depth_pyramid.first_level_bind_group =
+                Some(render_device.create_bind_group(&BindGroupDescriptor {
+                    label: Some("depth_pyramid_downsample_bind_group"),
+                    layout,
+                    entries: &[
+                        BindGroupEntry {
+                            binding: 0,
+                            resource: BindingResource::TextureView(&depth.view),
+                        },
+                        BindGroupEntry {
+                            binding: 1,
+                            resource: BindingResource::TextureView(&depth_pyramid.mip_views[0]),
+                        },
+                    ],
+                }));
+            depth_pyramid.first_level_source = Some(depth.view.id());
+        }
+    }
+    depth_pyramids
+        .0
+        .retain(|view_entity, _| in_use.contains(view_entity));
+}
+
+// █████████████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+// █░░░░░░░░░░░░░░█░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░█████████░░░░░░░░░░█░░░░░░██████████░░░░░░█░░░░░░░░░░░░░░█
+// █░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░█████████░░▄▀▄▀▄▀░░█░░▄▀░░░░░░░░░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░▄▀░░░░░░▄▀░░█░░░░▄▀░░░░█░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░░░█░░▄▀░░█████████░░░░▄▀░░░░█░░▄▀▄▀▄▀▄▀▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█
+// █░░▄▀░░██░░▄▀░░███░░▄▀░░███░░▄▀░░██░░▄▀░░█░░▄▀░░█████████░░▄▀░░███████████░░▄▀░░███░░▄▀░░░░░░▄▀░░██░░▄▀░░█░░▄▀░░█████████
+// █░░▄▀░░░░░░▄▀░░███░░▄▀░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░░░░░░░░░█░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█
+// █░░▄▀▄▀▄▀▄▀▄▀░░███░░▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░▄▀░░░░░░░░░░███░░▄▀░░███░░▄▀░░░░░░░░░░█░░▄▀░░░░░░░░░░█░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░░░█
+// █░░▄▀░░███████████░░▄▀░░███░░▄▀░░█████████░░▄▀░░█████████░░▄▀░░███████████░░▄▀░░███░░▄▀░░██░░▄▀░░░░░░▄▀░░█░░▄▀░░█████████
+// █░░▄▀░░█████████░░░░▄▀░░░░█░░▄▀░░█████████░░▄▀░░░░░░░░░░█░░▄▀░░░░░░░░░░█░░░░▄▀░░░░█░░▄▀░░██░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░░░░░░░░░█
+// █░░▄▀░░█████████░░▄▀▄▀▄▀░░█░░▄▀░░█████████░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀▄▀▄▀░░█░░▄▀░░██░░░░░░░░░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀░░█
+// █░░░░░░█████████░░░░░░░░░░█░░░░░░█████████░░░░░░░░░░░░░░█░░░░░░░░░░░░░░█░░░░░░░░░░█░░░░░░██████████░░░░░░█░░░░░░░░░░░░░░█
+// █████████████████████████████████████████████████████████████████████████████████████████████████████████████████████████
+
+fn storage_buffer_entry(binding: u32, read_only: bool) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Storage { read_only },
+            has_dynamic_offset: false,
+            min_binding_size: None,
+        },
+        count: None,
+    }
+}
+
+fn uniform_buffer_entry<T>(binding: u32) -> BindGroupLayoutEntry {
+    BindGroupLayoutEntry {
+        binding,
+        visibility: ShaderStages::COMPUTE,
+        ty: BindingType::Buffer {
+            ty: BufferBindingType::Uniform,
+            has_dynamic_offset: false,
+            min_binding_size: BufferSize::new(std::mem::size_of::<T>() as u64),
+        },
+        count: None,
+    }
+}
+
+#[derive(Resource)]
+pub struct DepthPyramidPipeline {
+    first_level: CachedComputePipelineId,
+    first_level_multisampled: CachedComputePipelineId,
+    level: CachedComputePipelineId,
+    first_level_layout: BindGroupLayout,
+    first_level_multisampled_layout: BindGroupLayout,
+    level_layout: BindGroupLayout,
+    occlusion_layout: BindGroupLayout, //Group 1 of the culling shaders
+    _occlusion_shader: Handle<Shader>, //Imported by the culling shaders, only loaded if someone holds the handle
+}
+
+impl FromWorld for DepthPyramidPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let downsample_layout = |label, source| {
+            render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+                entries: &[
+                    BindGroupLayoutEntry {
+                        binding: 0,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: source,
+                        count: None,
+                    },
+                    BindGroupLayoutEntry {
+                        binding: 1,
+                        visibility: ShaderStages::COMPUTE,
+                        ty: BindingType::StorageTexture {
+                            access: StorageTextureAccess::WriteOnly,
+                            format: TextureFormat::R32Float,
+                            view_dimension: TextureViewDimension::D2,
+                        },
+                        count: None,
+                    },
+                ],
+                label: Some(label),
+            })
+        };
+        let depth_texture = |multisampled| BindingType::Texture {
+            sample_type: TextureSampleType::Depth,
+            view_dimension: TextureViewDimension::D2,
+            multisampled,
+        };
+        let pyramid_texture = BindingType::Texture {
+            sample_type: TextureSampleType::Float { filterable: false },
+            view_dimension: TextureViewDimension::D2,
+            multisampled: false,
+        };
+        let first_level_layout = downsample_layout(
+            "depth_pyramid_first_level_bind_group_layout",
+            depth_texture(false),
+        );
+        let first_level_multisampled_layout = downsample_layout(
+            "depth_pyramid_first_level_multisampled_bind_group_layout",
+            depth_texture(true),
+        );
+        let level_layout =
+            downsample_layout("depth_pyramid_level_bind_group_layout", pyramid_texture);
+
+        let occlusion_layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_buffer_entry::<GpuOcclusionData>(0),
+                BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: ShaderStages::COMPUTE,
+                    ty: pyramid_texture,
+                    count: None,
+                },
+            ],
+            label: Some("occlusion_bind_group_layout"),
+        });
+
+        let asset_server = world.resource::<AssetServer>();
+        let shader: Handle<Shader> = asset_server.load("shaders/depth_pyramid.wgsl");
+        let occlusion_shader = asset_server.load("shaders/occlusion.wgsl");
+
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let queue_pipeline = |layout: &BindGroupLayout, shader_defs: Vec<ShaderDefVal>| {
+            pipeline_cache.queue_compute_pipeline(ComputePipelineDescriptor {
+                label: Some("depth_pyramid_pipeline".into()),
+                layout: vec![layout.clone()],
+                push_constant_ranges: Vec::new(),
+                shader: shader.clone(),
+                shader_defs,
+                entry_point: "downsample".into(),
+            })
+        };
+        let first_level = queue_pipeline(&first_level_layout, vec!["FIRST_LEVEL".into()]);
+        let first_level_multisampled = queue_pipeline(
+            &first_level_multisampled_layout,
+            vec!["FIRST_LEVEL".into(), "MULTISAMPLED".into()],
+        );
+        let level = queue_pipeline(&level_layout, Vec::new());
+
+        DepthPyramidPipeline {
+            first_level,
+            first_level_multisampled,
+            level,
+            first_level_layout,
+            first_level_multisampled_layout,
+            level_layout,
+            occlusion_layout,
+            _occlusion_shader: occlusion_shader,
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct InstanceCullingPipeline {
+    shader: Handle<Shader>,
+    _functions_shader: Handle<Shader>, //Imported by the culling shader, only loaded if someone holds the handle
+    layout: BindGroupLayout,
+    occlusion_layout: BindGroupLayout,
+}
+
+impl FromWorld for InstanceCullingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_buffer_entry::<GpuCullingView>(0),
+                storage_buffer_entry(1, true),  //instances
+                storage_buffer_entry(2, false), //visible instances
+                storage_buffer_entry(3, false), //indirect args
+                storage_buffer_entry(4, true),  //chunks
+            ],
+            label: Some("instance_culling_bind_group_layout"),
+        });
+
+        let asset_server = world.resource::<AssetServer>();
+        let shader = asset_server.load("shaders/chunk_instance_culling.wgsl");
+        let functions_shader = asset_server.load("shaders/chunk_instancing_functions.wgsl");
+
+        InstanceCullingPipeline {
+            shader,
+            _functions_shader: functions_shader,
+            layout,
+            occlusion_layout: world
+                .resource::<DepthPyramidPipeline>()
+                .occlusion_layout
+                .clone(),
+        }
+    }
+}
+
+impl SpecializedComputePipeline for InstanceCullingPipeline {
+    type Key = InstanceFormat;
+
+    fn specialize(&self, key: Self::Key) -> ComputePipelineDescriptor {
+        let mut shader_defs = Vec::new();
+        if key.layout == InstanceLayout::Full {
+            shader_defs.push("FULL_INSTANCE_TRANSFORM".into());
+        }
+        if key.tinted {
+            shader_defs.push("INSTANCE_TINT".into());
+        }
+        ComputePipelineDescriptor {
+            label: Some("instance_culling_pipeline".into()),
+            layout: vec![self.layout.clone(), self.occlusion_layout.clone()],
+            push_constant_ranges: Vec::new(),
+            shader: self.shader.clone(),
+            shader_defs,
+            entry_point: "cull".into(),
+        }
+    }
+}
+
+#[derive(Resource)]
+pub struct GrassCullingPipeline {
+    pipeline: CachedComputePipelineId,
+    layout: BindGroupLayout,
+}
+
+impl FromWorld for GrassCullingPipeline {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+
+        let layout = render_device.create_bind_group_layout(&BindGroupLayoutDescriptor {
+            entries: &[
+                uniform_buffer_entry::<GpuGrassCullingData>(0),
+                storage_buffer_entry(1, false), //indirect args
+            ],
+            label: Some("grass_culling_bind_group_layout"),
+        });
+        let occlusion_layout = world
+            .resource::<DepthPyramidPipeline>()
+            .occlusion_layout
+            .clone();
+
+        let shader = world
+            .resource::<AssetServer>()
+            .load("shaders/grass_culling.wgsl");
+        let pipeline =
+            world
+                .resource::<PipelineCache>()
+                .queue_compute_pipeline(ComputePipelineDescriptor {
+                    label: Some("grass_culling_pipeline".into()),
+                    layout: vec![layout.clone(), occlusion_layout],
+                    push_constant_ranges: Vec::new(),
+                    shader,
+                    shader_defs: Vec::new(),
+                    entry_point: "cull".into(),
+                });
+
+        GrassCullingPipeline { pipeline, layout }
+    }
+}
+
+// █████████████████████████████████████████████████████████████████████████
+// █░░░░░░░░░░░░███░░░░░░░░░░░░░░░░███░░░░░░░░░░░░░░█░░░░░░██████████░░░░░░█
+// █░░▄▀▄▀▄▀▄▀░░░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░██████████░░▄▀░░█
+// █░░▄▀░░░░▄▀▄▀░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░██████████░░▄▀░░█
+// █░░▄▀░░██░░▄▀░░█░░▄▀░░████░░▄▀░░███░░▄▀░░██░░▄▀░░█░░▄▀░░██████████░░▄▀░░█
+// █░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░░░▄▀░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░██░░░░░░██░░▄▀░░█
+// █░░▄▀░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀░░███░░▄▀▄▀▄▀▄▀▄▀░░█░░▄▀░░██░░▄▀░░██░░▄▀░░█
+// █░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░▄▀░░░░███░░▄▀░░░░░░▄▀░░█░░▄▀░░██░░▄▀░░██░░▄▀░░█
+// █░░▄▀░░██░░▄▀░░█░░▄▀░░██░░▄▀░░█████░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░▄▀░░░░░░▄▀░░█
+// █░░▄▀░░░░▄▀▄▀░░█░░▄▀░░██░░▄▀░░░░░░█░░▄▀░░██░░▄▀░░█░░▄▀▄▀▄▀▄▀▄▀▄▀▄▀▄▀▄▀░░█
+// █░░▄▀▄▀▄▀▄▀░░░░█░░▄▀░░██░░▄▀▄▀▄▀░░█░░▄▀░░██░░▄▀░░█░░▄▀░░░░░░▄▀░░░░░░▄▀░░█
+// █░░░░░░░░░░░░███░░░░░░██░░░░░░░░░░█░░░░░░██░░░░░░█░░░░░░██░░░░░░██░░░░░░█
+// █████████████████████████████████████████████████████████████████████████
+
+const MAX_WORKGROUPS: u32 = 65535;
+
+//Runs the culling for every chunk seen by the view before the main passes draw them
+#[derive(Default)]
+pub struct InstanceCullingNode;
+
+impl ViewNode for InstanceCullingNode {
+    type ViewQuery = Entity;
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        view_entity: QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let culled_instance_buffers = world.resource::<CulledInstanceBuffers>();
+        let occluded_grass_buffers = world.resource::<OccludedGrassBuffers>();
+        let grass_culling_pipeline = world.resource::<GrassCullingPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(depth_pyramid) = world.resource::<DepthPyramids>().0.get(&view_entity) else {
+            return Ok(());
+        };
+        let occlusion_bind_group = &depth_pyramid.occlusion_bind_group;
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("chunk_instance_culling_pass"),
+                });
+        for ((view, _), culled_instances) in culled_instance_buffers.0.iter() {
+            if *view != view_entity || !culled_instances.ready {
+                continue;
+            }
+            let Some(pipeline) = pipeline_cache.get_compute_pipeline(culled_instances.pipeline)
+            else {
+                continue;
+            };
+            let Some(bind_group) = &culled_instances.bind_group else {
+                continue;
+            };
+            pass.set_pipeline(pipeline);
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.set_bind_group(1, occlusion_bind_group, &[]);
+            //One dispatch for the whole batch, every instance counts into the draw args of its chunk.
+            //Spills over into y since a dimension only takes 65535 workgroups
+            let workgroups = culled_instances.nr_instances.div_ceil(64);
+            pass.dispatch_workgroups(
+                workgroups.min(MAX_WORKGROUPS),
+                workgroups.div_ceil(MAX_WORKGROUPS),
+                1,
+            );
+        }
+
+        let Some(grass_pipeline) =
+            pipeline_cache.get_compute_pipeline(grass_culling_pipeline.pipeline)
+        else {
+            return Ok(());
+        };
+        pass.set_pipeline(grass_pipeline);
+        pass.set_bind_group(1, occlusion_bind_group, &[]);
+        for ((view, _), occluded_grass) in occluded_grass_buffers.0.iter() {
+            if *view != view_entity || !occluded_grass.ready {
+                continue;
+            }
+            pass.set_bind_group(0, &occluded_grass.bind_group, &[]);
+            pass.dispatch_workgroups(1, 1, 1);
+        }
+
+        Ok(())
+    }
+}
+
+//Builds the depth pyramid from the depth of the opaque pass, the culling of the next frame tests against it
+#[derive(Default)]
+pub struct DepthPyramidNode;
+
+impl ViewNode for DepthPyramidNode {
+    type ViewQuery = Entity;
+
+    fn run(
+        &self,
+        _graph: &mut RenderGraphContext,
+        render_context: &mut RenderContext,
+        view_entity: QueryItem<Self::ViewQuery>,
+        world: &World,
+    ) -> Result<(), NodeRunError> {
+        let pyramid_pipeline = world.resource::<DepthPyramidPipeline>();
+        let pipeline_cache = world.resource::<PipelineCache>();
+        let Some(depth_pyramid) = world.resource::<DepthPyramids>().0.get(&view_entity) else {
+            return Ok(());
+        };
+        if !depth_pyramid.built {
+            return Ok(());
+        }
+        let first_level = match depth_pyramid.multisampled {
+            true => pyramid_pipeline.first_level_multisampled,
+            false => pyramid_pipeline.first_level,
+        };
+        let (Some(first_level_pipeline), Some(level_pipeline)) = (
+            pipeline_cache.get_compute_pipeline(first_level),
+            pipeline_cache.get_compute_pipeline(pyramid_pipeline.level),
+        ) else {
+            return Ok(());
+        };
+
+        let mut pass =
+            render_context
+                .command_encoder()
+                .begin_compute_pass(&ComputePassDescriptor {
+                    label: Some("chunk_depth_pyramid_pass"),
+                });
+        let Some(first_level_bind_group) = &depth_pyramid.first_level_bind_group else {
+            return Ok(());
+        };
+        let bind_groups =
+            std::iter::once(first_level_bind_group).chain(&depth_pyramid.level_bind_groups);
+        for (mip, bind_group) in bind_groups.enumerate() {
+            let size = UVec2::new(
+                (depth_pyramid.size.x >> mip).max(1),
+                (depth_pyramid.size.y >> mip).max(1),
+            );
+            pass.set_pipeline(match mip {
+                0 => first_level_pipeline,
+                _ => level_pipeline,
+            });
+            pass.set_bind_group(0, bind_group, &[]);
+            pass.dispatch_workgroups(size.x.div_ceil(8), size.y.div_ceil(8), 1);
+        }
+
+        Ok(())
+    }
+}
@@ -1,7 +1,18 @@
-use bevy::prelude::*;
+use bevy::{
+    core_pipeline::tonemapping::{DebandDither, Tonemapping},
+    pbr::{EnvironmentMapLight, MeshPipelineKey, ScreenSpaceAmbientOcclusionSettings},
+    prelude::*,
+    render::{
+        render_asset::RenderAssets,
+        view::{ExtractedView, Msaa},
+    },
+};
 
 pub mod chunk_grass;
 pub mod chunk_instancing;
+pub mod gpu_culling;
+pub mod scatter;
+pub mod wind;
 
 #[derive(Component, Debug)]
 pub struct DistanceCulling {
@@ -18,3 +29,181 @@ impl Default for DistanceCulling {
 pub struct Chunk {
     pub chunk_xy: [u32; 2],
 }
+
+impl Chunk {
+    //Seed for placing things in this chunk, the same world seed always gives the same chunk seed.
+    //Layer tells apart the different things placed in the same chunk, otherwise they would all end up on the same spots
+    pub fn seed(&self, world_seed: u64, layer: u32) -> u64 {
+        let mut seed = world_seed;
+        for value in [self.chunk_xy[0], self.chunk_xy[1], layer] {
+            seed = split_mix(seed ^ value as u64);
+        }
+        seed
+    }
+}
+
+//SplitMix64, spreads nearby inputs over the whole u64 range so neighbouring chunks don't get similar seeds
+pub(crate) fn split_mix(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+//Same view key as bevy uses for its pbr meshes so that our custom pipelines are lit and tonemapped the same way
+pub(crate) fn pbr_view_key(
+    msaa: &Msaa,
+    view: &ExtractedView,
+    tonemapping: Option<&Tonemapping>,
+    dither: Option<&DebandDither>,
+    environment_map: Option<&EnvironmentMapLight>,
+    ssao: Option<&ScreenSpaceAmbientOcclusionSettings>,
+    images: &RenderAssets<Image>,
+) -> MeshPipelineKey {
+    let mut view_key =
+        MeshPipelineKey::from_msaa_samples(msaa.samples()) | MeshPipelineKey::from_hdr(view.hdr);
+
+    if let Some(environment_map) = environment_map {
+        if environment_map.is_loaded(images) {
+            view_key |= MeshPipelineKey::ENVIRONMENT_MAP;
+        }
+    }
+
+    if !view.hdr {
+        if let Some(tonemapping) = tonemapping {
+            view_key |= MeshPipelineKey::TONEMAP_IN_SHADER;
+            view_key |= match tonemapping {
+                Tonemapping::None => MeshPipelineKey::TONEMAP_METHOD_NONE,
+                Tonemapping::Reinhard => MeshPipelineKey::TONEMAP_METHOD_REINHARD,
+                Tonemapping::ReinhardLuminance => {
+                    MeshPipelineKey::TONEMAP_METHOD_REINHARD_LUMINANCE
+                }
+                Tonemapping::AcesFitted => MeshPipelineKey::TONEMAP_METHOD_ACES_FITTED,
+                Tonemapping::AgX => MeshPipelineKey::TONEMAP_METHOD_AGX,
+                Tonemapping::SomewhatBoringDisplayTransform => {
+                    MeshPipelineKey::TONEMAP_METHOD_SOMEWHAT_BORING_DISPLAY_TRANSFORM
+                }
+                Tonemapping::TonyMcMapface => MeshPipelineKey::TONEMAP_METHOD_TONY_MC_MAPFACE,
+                Tonemapping::BlenderFilmic => MeshPipelineKey::TONEMAP_METHOD_BLENDER_FILMIC,
+            };
+        }
+        if let Some(DebandDither::Enabled) = dither {
+            view_key |= MeshPipelineKey::DEBAND_DITHER;
+        }
+    }
+
+    if ssao.is_some() {
+        view_key |= MeshPipelineKey::SCREEN_SPACE_AMBIENT_OCCLUSION;
+    }
+
+    view_key
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::render::render_resource::{Extent3d, TextureDimension, TextureFormat};
+
+    use super::{
+        chunk_grass::GridConfig,
+        chunk_instancing::ChunkInstancing,
+        scatter::{DensityMap, HeightSource},
+        *,
+    };
+
+    const WORLD_SEED: u64 = 1234;
+    const CHUNK_SIZE: f32 = 20.0;
+
+    fn seeded(chunk_xy: [u32; 2], layer: u32) -> ChunkInstancing {
+        ChunkInstancing::new_seeded(
+            100,
+            Handle::default(),
+            Transform::IDENTITY,
+            CHUNK_SIZE,
+            WORLD_SEED,
+            &Chunk { chunk_xy },
+            layer,
+        )
+    }
+
+    fn positions(chunk_instancing: &ChunkInstancing) -> Vec<[f32; 4]> {
+        chunk_instancing
+            .instances
+            .iter()
+            .map(|instance| instance.pos_xyz)
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_chunk_and_layer_gives_same_instances() {
+        assert_eq!(positions(&seeded([3, 2], 0)), positions(&seeded([3, 2], 0)));
+    }
+
+    #[test]
+    fn other_layer_or_chunk_gives_other_instances() {
+        let instances = positions(&seeded([3, 2], 0));
+        assert_ne!(instances, positions(&seeded([3, 2], 1)));
+        assert_ne!(instances, positions(&seeded([4, 2], 0)));
+        assert_ne!(instances, positions(&seeded([3, 3], 0)));
+    }
+
+    #[test]
+    fn random_tint_keeps_placement() {
+        let tinted = seeded([3, 2], 0).with_random_tint(Color::BLACK, Color::WHITE);
+        assert_eq!(positions(&seeded([3, 2], 0)), positions(&tinted));
+    }
+
+    //Same density everywhere over the chunk at the origin
+    fn flat_density_map(density: u8) -> DensityMap {
+        let image = Image::new(
+            Extent3d {
+                width: 2,
+                height: 2,
+                depth_or_array_layers: 1,
+            },
+            TextureDimension::D2,
+            vec![density; 4],
+            TextureFormat::R8Unorm,
+        );
+        let grid_config = GridConfig {
+            grid_center_xy: [CHUNK_SIZE / 2.0, CHUNK_SIZE / 2.0],
+            grid_half_extents: [CHUNK_SIZE / 2.0, CHUNK_SIZE / 2.0],
+            ..default()
+        };
+        DensityMap::from_image(&image, 0, &grid_config).unwrap()
+    }
+
+    #[test]
+    fn density_map_thins_out_instances() {
+        let empty = seeded([3, 2], 0).with_density_map(&flat_density_map(0), Vec2::ZERO);
+        assert!(empty.instances.is_empty());
+        let full = seeded([3, 2], 0).with_density_map(&flat_density_map(255), Vec2::ZERO);
+        assert_eq!(positions(&seeded([3, 2], 0)), positions(&full));
+    }
+
+    #[test]
+    fn height_follows_the_terrain() {
+        let ramp = |xy: Vec2| 0.1 * xy.x + 0.05 * xy.y;
+        let chunk_origin = Vec2::new(40.0, 60.0);
+        let placed =
+            seeded([3, 2], 0).with_height(&HeightSource::from_fn(ramp), chunk_origin, None);
+        assert_eq!(placed.instances.len(), seeded([3, 2], 0).instances.len());
+        for instance in &placed.instances {
+            let world_xy = chunk_origin + Vec2::new(instance.pos_xyz[0], instance.pos_xyz[1]);
+            assert_eq!(instance.pos_xyz[2], ramp(world_xy));
+        }
+    }
+
+    #[test]
+    fn max_slope_removes_instances_on_steep_ground() {
+        let max_slope = Some(30_f32.to_radians());
+        let steep = seeded([3, 2], 0).with_height(
+            &HeightSource::from_fn(|xy| 2.0 * xy.x),
+            Vec2::ZERO,
+            max_slope,
+        );
+        assert!(steep.instances.is_empty());
+        let flat =
+            seeded([3, 2], 0).with_height(&HeightSource::from_fn(|_| 3.0), Vec2::ZERO, max_slope);
+        assert_eq!(flat.instances.len(), seeded([3, 2], 0).instances.len());
+    }
+}
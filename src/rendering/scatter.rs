@@ -0,0 +1,533 @@
+use std::{f32::consts::SQRT_2, sync::Arc};
+
+use bevy::{prelude::*, render::render_resource::TextureFormat, utils::HashMap};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::{chunk_grass::GridConfig, split_mix, Chunk};
+
+//Poisson-disk scattering of instance positions, no two points of the same Scatter end up closer than min_distance.
+//Every chunk samples its own points from the world seed, points near the border are checked against the points of the
+//neighbouring chunks which are sampled again from their seeds, so chunks agree on the border without knowing about each other.
+//Distances have to be smaller than the chunk size since only the closest neighbours are checked.
+#[derive(Clone, Debug)]
+pub struct Scatter {
+    pub layer: u32, //Same as the layer of Chunk::seed, use one per kind of thing placed
+    pub min_distance: f32, //Between two points of this Scatter
+    pub max_instances: Option<usize>, //Per chunk, the rest is thinned out evenly. None fills the chunk as tight as min_distance allows
+    pub exclusions: Vec<Exclusion>,
+    pub density: Option<ScatterDensity>,
+}
+
+//Thins out the points where the density map is below 1, max_instances is then the count at full density
+#[derive(Clone, Debug)]
+pub struct ScatterDensity {
+    pub map: Arc<DensityMap>,
+    pub world_origin: Vec2, //World position of the corner of chunk [0,0], chunks are laid out chunk_size apart from there
+}
+
+//Keeps the points of a Scatter away from the points of another one, e.g. no bushes within 1m of a tree
+#[derive(Clone, Debug)]
+pub struct Exclusion {
+    pub scatter: Scatter,
+    pub distance: f32,
+}
+
+#[derive(Clone, Copy)]
+struct ScatterPoint {
+    position: Vec2, //In the chunk, from 0 to chunk_size
+    priority: u64,  //Decides which point stays when points of neighbouring chunks are too close
+}
+
+//Attempts around a point before it's considered surrounded, 30 is what Bridson uses
+const ATTEMPTS: u32 = 30;
+
+impl Scatter {
+    pub fn new(layer: u32, min_distance: f32) -> Self {
+        assert!(
+            min_distance > 0.0,
+            "Scatter min_distance has to be positive"
+        );
+        Self {
+            layer,
+            min_distance,
+            max_instances: None,
+            exclusions: Vec::new(),
+            density: None,
+        }
+    }
+
+    pub fn with_density_map(mut self, map: Arc<DensityMap>, world_origin: Vec2) -> Self {
+        self.density = Some(ScatterDensity { map, world_origin });
+        self
+    }
+
+    pub fn with_max_instances(mut self, max_instances: usize) -> Self {
+        self.max_instances = Some(max_instances);
+        self
+    }
+
+    //Other is placed first and this one fills in around it
+    pub fn excluding(mut self, other: &Scatter, distance: f32) -> Self {
+        self.exclusions.push(Exclusion {
+            scatter: other.clone(),
+            distance,
+        });
+        self
+    }
+
+    //Positions in the chunk, from (0,0) to (chunk_size, chunk_size). The same seed and chunk always gives the same points
+    pub fn points(&self, world_seed: u64, chunk: &Chunk, chunk_size: f32) -> Vec<Vec2> {
+        self.scatter_points(world_seed, chunk.chunk_xy, chunk_size)
+            .into_iter()
+            .map(|point| point.position)
+            .collect()
+    }
+
+    fn scatter_points(
+        &self,
+        world_seed: u64,
+        chunk_xy: [u32; 2],
+        chunk_size: f32,
+    ) -> Vec<ScatterPoint> {
+        //A point too close to a point of a neighbouring chunk is only kept if it has the higher priority,
+        //both chunks come to the same conclusion since they sample the same points
+        let mut neighbour_points = PointGrid::new(self.min_distance);
+        for (offset, neighbour_xy) in neighbours(chunk_xy) {
+            if offset == IVec2::ZERO {
+                continue;
+            }
+            for point in self.candidates(world_seed, neighbour_xy, chunk_size) {
+                neighbour_points.insert(
+                    point.position + offset.as_vec2() * chunk_size,
+                    point.priority,
+                );
+            }
+        }
+        let mut points: Vec<ScatterPoint> = self
+            .candidates(world_seed, chunk_xy, chunk_size)
+            .into_iter()
+            .filter(|point| {
+                !neighbour_points.any_within(point.position, self.min_distance, |priority| {
+                    priority > point.priority
+                })
+            })
+            .collect();
+
+        //Priorities are random so dropping the lowest ones thins out the whole chunk evenly
+        if let Some(max_instances) = self.max_instances {
+            points.sort_by_key(|point| std::cmp::Reverse(point.priority));
+            points.truncate(max_instances);
+        }
+
+        //Decided from the priority so neighbouring chunks and exclusions agree on which points are left
+        if let Some(density) = &self.density {
+            let chunk_origin = density.world_origin
+                + Vec2::new(chunk_xy[0] as f32, chunk_xy[1] as f32) * chunk_size;
+            points.retain(|point| {
+                let chance = (split_mix(point.priority) >> 40) as f32 / (1 << 24) as f32;
+                chance < density.map.density(chunk_origin + point.position)
+            });
+        }
+
+        for exclusion in &self.exclusions {
+            let mut excluded_points = PointGrid::new(exclusion.distance);
+            for (offset, neighbour_xy) in neighbours(chunk_xy) {
+                for point in exclusion
+                    .scatter
+                    .scatter_points(world_seed, neighbour_xy, chunk_size)
+                {
+                    excluded_points.insert(point.position + offset.as_vec2() * chunk_size, 0);
+                }
+            }
+            points.retain(|point| {
+                !excluded_points.any_within(point.position, exclusion.distance, |_| true)
+            });
+        }
+        points
+    }
+
+    //Bridson's algorithm inside one chunk, without looking at the neighbours
+    fn candidates(
+        &self,
+        world_seed: u64,
+        chunk_xy: [u32; 2],
+        chunk_size: f32,
+    ) -> Vec<ScatterPoint> {
+        let mut rng = ChaCha8Rng::seed_from_u64(Chunk { chunk_xy }.seed(world_seed, self.layer));
+        //Cells small enough to hold at most one point
+        let cell_size = self.min_distance / SQRT_2;
+        let grid_side = (chunk_size / cell_size).ceil().max(1.0) as usize;
+        let cell = |position: Vec2| {
+            (
+                ((position.x / cell_size) as usize).min(grid_side - 1),
+                ((position.y / cell_size) as usize).min(grid_side - 1),
+            )
+        };
+        let mut grid: Vec<Option<usize>> = vec![None; grid_side * grid_side];
+        let mut points: Vec<ScatterPoint> = Vec::new();
+        let mut active = Vec::new();
+
+        let first = Vec2::new(rng.gen::<f32>(), rng.gen::<f32>()) * chunk_size;
+        let (x, y) = cell(first);
+        grid[y * grid_side + x] = Some(0);
+        points.push(ScatterPoint {
+            position: first,
+            priority: rng.gen(),
+        });
+        active.push(0);
+
+        while !active.is_empty() {
+            let active_index = rng.gen_range(0..active.len());
+            let around = points[active[active_index]].position;
+            let mut found = false;
+            for _ in 0..ATTEMPTS {
+                //Somewhere between min_distance and twice that away. Picked from a square instead of with sin/cos
+                //since those come from the platform's libm and may differ between machines, this only adds and multiplies
+                let offset = loop {
+                    let offset = (Vec2::new(rng.gen::<f32>(), rng.gen::<f32>()) * 4.0 - 2.0)
+                        * self.min_distance;
+                    let distance_squared =
+                        offset.length_squared() / (self.min_distance * self.min_distance);
+                    if (1.0..=4.0).contains(&distance_squared) {
+                        break offset;
+                    }
+                };
+                let position = around + offset;
+                if position.cmplt(Vec2::ZERO).any() || position.cmpge(Vec2::splat(chunk_size)).any()
+                {
+                    continue;
+                }
+
+                let (x, y) = cell(position);
+                let too_close = (y.saturating_sub(2)..(y + 3).min(grid_side)).any(|cell_y| {
+                    (x.saturating_sub(2)..(x + 3).min(grid_side)).any(|cell_x| {
+                        grid[cell_y * grid_side + cell_x].is_some_and(|index| {
+                            points[index].position.distance_squared(position)
+                                < self.min_distance * self.min_distance
+                        })
+                    })
+                });
+                if too_close {
+                    continue;
+                }
+
+                grid[y * grid_side + x] = Some(points.len());
+                active.push(points.len());
+                points.push(ScatterPoint {
+                    position,
+                    priority: rng.gen(),
+                });
+                found = true;
+                break;
+            }
+            if !found {
+                active.swap_remove(active_index);
+            }
+        }
+        points
+    }
+}
+
+//The chunk itself and the chunks around it with their offset, chunks below 0 don't exist
+fn neighbours(chunk_xy: [u32; 2]) -> impl Iterator<Item = (IVec2, [u32; 2])> {
+    (-1..=1).flat_map(move |dy: i32| {
+        (-1..=1).filter_map(move |dx: i32| {
+            let x = chunk_xy[0].checked_add_signed(dx)?;
+            let y = chunk_xy[1].checked_add_signed(dy)?;
+            Some((IVec2::new(dx, dy), [x, y]))
+        })
+    })
+}
+
+//Points bucketed in cells as large as the distance looked for, so only the 3x3 cells around a position have to be checked
+struct PointGrid {
+    cell_size: f32,
+    cells: HashMap<IVec2, Vec<(Vec2, u64)>>,
+}
+
+impl PointGrid {
+    fn new(cell_size: f32) -> Self {
+        Self {
+            cell_size,
+            cells: HashMap::new(),
+        }
+    }
+
+    fn cell(&self, position: Vec2) -> IVec2 {
+        (position / self.cell_size).floor().as_ivec2()
+    }
+
+    fn insert(&mut self, position: Vec2, priority: u64) {
+        self.cells
+            .entry(self.cell(position))
+            .or_default()
+            .push((position, priority));
+    }
+
+    fn any_within(&self, position: Vec2, distance: f32, filter: impl Fn(u64) -> bool) -> bool {
+        let cell = self.cell(position);
+        (-1..=1).any(|dy| {
+            (-1..=1).any(|dx| {
+                self.cells
+                    .get(&(cell + IVec2::new(dx, dy)))
+                    .is_some_and(|points| {
+                        points.iter().any(|(other, priority)| {
+                            other.distance_squared(position) < distance * distance
+                                && filter(*priority)
+                        })
+                    })
+            })
+        })
+    }
+}
+
+//Density painted in an image, from 0 to 1. Covers the world the same way the grass growth textures do, see GridConfig
+#[derive(Clone, Debug)]
+pub struct DensityMap(WorldImage);
+
+impl DensityMap {
+    //Reads the red channel. Layer picks the texture of an array texture like GrowthTextures, use 0 otherwise.
+    //None if the image format isn't supported or the layer is missing
+    pub fn from_image(image: &Image, layer: u32, grid_config: &GridConfig) -> Option<Self> {
+        WorldImage::from_image(image, layer, grid_config).map(Self)
+    }
+
+    pub fn density(&self, world_xy: Vec2) -> f32 {
+        self.0.sample(world_xy).clamp(0.0, 1.0)
+    }
+}
+
+//Terrain height in world space, either from an image or from any function, e.g. the same noise the terrain mesh is built from
+#[derive(Clone)]
+pub enum HeightSource {
+    Map(Arc<HeightMap>),
+    Function(Arc<dyn Fn(Vec2) -> f32 + Send + Sync>),
+}
+
+//Distance between the heights a function is sampled at for the normal
+const FUNCTION_NORMAL_STEP: f32 = 0.1;
+
+impl HeightSource {
+    pub fn from_fn(height: impl Fn(Vec2) -> f32 + Send + Sync + 'static) -> Self {
+        Self::Function(Arc::new(height))
+    }
+
+    pub fn height(&self, world_xy: Vec2) -> f32 {
+        match self {
+            HeightSource::Map(height_map) => height_map.height(world_xy),
+            HeightSource::Function(height) => height(world_xy),
+        }
+    }
+
+    //Up facing terrain normal from the height differences around the position
+    pub fn normal(&self, world_xy: Vec2) -> Vec3 {
+        let step = match self {
+            HeightSource::Map(height_map) => height_map.texel_size(),
+            HeightSource::Function(_) => Vec2::splat(FUNCTION_NORMAL_STEP),
+        };
+        let dx = self.height(world_xy + Vec2::new(step.x, 0.0))
+            - self.height(world_xy - Vec2::new(step.x, 0.0));
+        let dy = self.height(world_xy + Vec2::new(0.0, step.y))
+            - self.height(world_xy - Vec2::new(0.0, step.y));
+        Vec3::new(-dx / (2.0 * step.x), -dy / (2.0 * step.y), 1.0).normalize()
+    }
+
+    //Angle between the terrain and the xy plane, in radians
+    pub fn slope(&self, world_xy: Vec2) -> f32 {
+        self.normal(world_xy).z.clamp(-1.0, 1.0).acos()
+    }
+}
+
+//Heights from the red channel of an image, 0 maps to min_height and 1 (or 1.0 for float formats) to max_height.
+//Covers the world like DensityMap
+#[derive(Clone, Debug)]
+pub struct HeightMap {
+    image: WorldImage,
+    pub min_height: f32,
+    pub max_height: f32,
+}
+
+impl HeightMap {
+    pub fn from_image(
+        image: &Image,
+        layer: u32,
+        grid_config: &GridConfig,
+        min_height: f32,
+        max_height: f32,
+    ) -> Option<Self> {
+        Some(Self {
+            image: WorldImage::from_image(image, layer, grid_config)?,
+            min_height,
+            max_height,
+        })
+    }
+
+    //Same terrain the grass is lifted onto, so trees and rocks placed with it stand on the grass
+    pub fn from_grid_config(grid_config: &GridConfig, images: &Assets<Image>) -> Option<Self> {
+        let image = images.get(grid_config.height_map.as_ref()?)?;
+        Self::from_image(
+            image,
+            0,
+            grid_config,
+            grid_config.min_height,
+            grid_config.max_height,
+        )
+    }
+
+    pub fn height(&self, world_xy: Vec2) -> f32 {
+        self.min_height + self.image.sample(world_xy) * (self.max_height - self.min_height)
+    }
+
+    //World size of one pixel
+    fn texel_size(&self) -> Vec2 {
+        self.image.grid_config.get_size() / self.image.size.as_vec2()
+    }
+}
+
+//Same conversion the gpu does when loading from an srgb texture
+fn srgb_to_linear(value: u8) -> f32 {
+    let value = value as f32 / 255.0;
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+//One channel of an image laid over the world with the GridConfig mapping
+#[derive(Clone, Debug)]
+struct WorldImage {
+    size: UVec2,
+    values: Vec<f32>,
+    grid_config: GridConfig,
+}
+
+impl WorldImage {
+    fn from_image(image: &Image, layer: u32, grid_config: &GridConfig) -> Option<Self> {
+        let (bytes_per_pixel, read): (usize, fn(&[u8]) -> f32) =
+            match image.texture_descriptor.format {
+                TextureFormat::R8Unorm => (1, |bytes| bytes[0] as f32 / 255.0),
+                TextureFormat::Rgba8Unorm => (4, |bytes| bytes[0] as f32 / 255.0),
+                TextureFormat::Bgra8Unorm => (4, |bytes| bytes[2] as f32 / 255.0),
+                //The shaders read srgb textures as linear values, so the cpu has to do the same
+                TextureFormat::Rgba8UnormSrgb => (4, |bytes| srgb_to_linear(bytes[0])),
+                TextureFormat::Bgra8UnormSrgb => (4, |bytes| srgb_to_linear(bytes[2])),
+                TextureFormat::R16Unorm => (2, |bytes| {
+                    u16::from_le_bytes([bytes[0], bytes[1]]) as f32 / u16::MAX as f32
+                }),
+                TextureFormat::R32Float => (4, |bytes| {
+                    f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                }),
+                TextureFormat::Rgba32Float => (16, |bytes| {
+                    f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+                }),
+                _ => return None,
+            };
+        let extent = image.texture_descriptor.size;
+        if layer >= extent.depth_or_array_layers {
+            return None;
+        }
+        let layer_len = (extent.width * extent.height) as usize * bytes_per_pixel;
+        let start = layer as usize * layer_len;
+        let values = image
+            .data
+            .get(start..start + layer_len)?
+            .chunks_exact(bytes_per_pixel)
+            .map(read)
+            .collect();
+
+        Some(Self {
+            size: UVec2::new(extent.width, extent.height),
+            values,
+            grid_config: grid_config.clone(),
+        })
+    }
+
+    //Bilinear like the texture sampler, positions outside the grid get the value of the closest edge
+    fn sample(&self, world_xy: Vec2) -> f32 {
+        let texel = self.grid_config.world_to_uv(world_xy) * self.size.as_vec2() - 0.5;
+        let base = texel.floor();
+        let fraction = texel - base;
+        let base = base.as_ivec2();
+        let value = |x: i32, y: i32| {
+            let x = x.clamp(0, self.size.x as i32 - 1) as u32;
+            let y = y.clamp(0, self.size.y as i32 - 1) as u32;
+            self.values[(y * self.size.x + x) as usize]
+        };
+        let bottom =
+            value(base.x, base.y) * (1.0 - fraction.x) + value(base.x + 1, base.y) * fraction.x;
+        let top = value(base.x, base.y + 1) * (1.0 - fraction.x)
+            + value(base.x + 1, base.y + 1) * fraction.x;
+        bottom * (1.0 - fraction.y) + top * fraction.y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const WORLD_SEED: u64 = 1234;
+    const CHUNK_SIZE: f32 = 20.0;
+
+    fn chunk(x: u32, y: u32) -> Chunk {
+        Chunk { chunk_xy: [x, y] }
+    }
+
+    #[test]
+    fn same_seed_and_chunk_gives_same_points() {
+        let scatter = Scatter::new(0, 1.5);
+        let points = scatter.points(WORLD_SEED, &chunk(3, 2), CHUNK_SIZE);
+        assert!(!points.is_empty());
+        assert_eq!(points, scatter.points(WORLD_SEED, &chunk(3, 2), CHUNK_SIZE));
+        assert_ne!(
+            points,
+            scatter.points(WORLD_SEED + 1, &chunk(3, 2), CHUNK_SIZE)
+        );
+    }
+
+    #[test]
+    fn points_keep_min_distance_across_chunk_border() {
+        let scatter = Scatter::new(0, 1.5);
+        let left = scatter.points(WORLD_SEED, &chunk(1, 1), CHUNK_SIZE);
+        let right: Vec<Vec2> = scatter
+            .points(WORLD_SEED, &chunk(2, 1), CHUNK_SIZE)
+            .into_iter()
+            .map(|point| point + Vec2::new(CHUNK_SIZE, 0.0))
+            .collect();
+        for a in &left {
+            for b in &right {
+                assert!(
+                    a.distance(*b) >= scatter.min_distance - 1e-4,
+                    "{a} and {b} are closer than min_distance"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn exclusion_keeps_distance_to_other_scatter() {
+        let trees = Scatter::new(0, 4.0);
+        let bushes = Scatter::new(1, 1.0).excluding(&trees, 2.0);
+        let bush_points = bushes.points(WORLD_SEED, &chunk(1, 1), CHUNK_SIZE);
+        assert!(!bush_points.is_empty());
+        for (offset, neighbour_xy) in neighbours([1, 1]) {
+            let tree_points = trees.points(
+                WORLD_SEED,
+                &Chunk {
+                    chunk_xy: neighbour_xy,
+                },
+                CHUNK_SIZE,
+            );
+            for tree in tree_points {
+                let tree = tree + offset.as_vec2() * CHUNK_SIZE;
+                for bush in &bush_points {
+                    assert!(
+                        bush.distance(tree) >= 2.0 - 1e-4,
+                        "bush at {bush} is too close to tree at {tree}"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use bevy::{
+    prelude::*,
+    render::{
+        extract_resource::{ExtractResource, ExtractResourcePlugin},
+        render_resource::*,
+        renderer::{RenderDevice, RenderQueue},
+        Render, RenderApp, RenderSet,
+    },
+    transform::TransformSystem,
+};
+use bytemuck::{Pod, Zeroable};
+
+//Wind shared by the grass and the instanced plants, change it at runtime for weather. The defaults are the wind the grass always had
+#[derive(Resource, Debug, Clone)]
+pub struct Wind {
+    pub direction: Vec2, //Direction the wind blows towards, does not need to be normalized
+    pub strength: f32,   //How far the straws and plants swing back and forth
+    pub gustiness: f32,  //Strength of the gusts, 0.0 turns them off
+    pub gust_scale: f32, //Higher value = smaller gust islands
+    pub gust_speed: f32, //How fast the gust islands travel with the wind
+    pub turbulence: f32, //Frequency of the small flutter of the straws and leaves
+}
+
+impl Default for Wind {
+    fn default() -> Self {
+        Self {
+            direction: Vec2::NEG_X,
+            strength: 0.4,
+            gustiness: 1.0,
+            gust_scale: 0.05,
+            gust_speed: 0.7,
+            turbulence: 2.0,
+        }
+    }
+}
+
+//Local wind on top of the global one, e.g a helicopter downdraft, an explosion or a dragon's wing beat
+#[derive(Component, Clone, Debug)]
+pub struct WindZone {
+    pub shape: WindZoneShape,
+    pub radius: f32,
+    pub strength: f32,         //At the center, 1.0 pushes grass flat
+    pub falloff: f32, //Exponent of the fade from the center to the radius, 1.0 is linear and higher keeps it to the center
+    pub lifetime: Option<f32>, //Seconds until the zone has faded out and is removed from the entity, None lasts until removed
+    age: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WindZoneShape {
+    Spherical,         //Pushes outwards from the center
+    Directional(Vec2), //Pushes everything inside the radius the same way
+}
+
+impl WindZone {
+    pub fn spherical(radius: f32, strength: f32) -> Self {
+        Self::new(WindZoneShape::Spherical, radius, strength)
+    }
+
+    pub fn directional(direction: Vec2, radius: f32, strength: f32) -> Self {
+        Self::new(WindZoneShape::Directional(direction), radius, strength)
+    }
+
+    fn new(shape: WindZoneShape, radius: f32, strength: f32) -> Self {
+        Self {
+            shape,
+            radius,
+            strength,
+            falloff: 1.0,
+            lifetime: None,
+            age: 0.0,
+        }
+    }
+
+    pub fn with_falloff(mut self, falloff: f32) -> Self {
+        self.falloff = falloff;
+        self
+    }
+
+    pub fn with_lifetime(mut self, seconds: f32) -> Self {
+        self.lifetime = Some(seconds);
+        self
+    }
+
+    //Fades out linearly over the lifetime
+    fn current_strength(&self) -> f32 {
+        match self.lifetime {
+            Some(lifetime) => self.strength * (1.0 - self.age / lifetime).max(0.0),
+            None => self.strength,
+        }
+    }
+}
+
+pub const MAX_WIND_ZONES: usize = 16; //Zones past this are ignored
+
+//Zones that are active this frame, ready for the gpu
+#[derive(Resource, Clone, Default)]
+pub struct WindZones {
+    zones: Vec<GpuWindZone>,
+}
+
+impl WindZones {
+    //Strongest push of any zone, for padding the culling bounds of swaying plants
+    pub(crate) fn max_strength(&self) -> f32 {
+        self.zones
+            .iter()
+            .map(|zone| zone.direction_strength[2].abs())
+            .fold(0.0, f32::max)
+    }
+}
+
+fn update_wind_zones(
+    mut commands: Commands,
+    mut wind_zones: ResMut<WindZones>,
+    mut zones: Query<(Entity, &GlobalTransform, &mut WindZone)>,
+    time: Res<Time>,
+) {
+    let mut gpu_zones = Vec::new();
+    for (entity, transform, mut zone) in &mut zones {
+        zone.age += time.delta_seconds();
+        if zone.lifetime.is_some_and(|lifetime| zone.age >= lifetime) {
+            commands.entity(entity).remove::<WindZone>();
+            continue;
+        }
+        if gpu_zones.len() < MAX_WIND_ZONES {
+            gpu_zones.push(zone.to_raw(transform.translation()));
+        }
+    }
+    //Only touch the resource when something changed so the buffer isn't rewritten every frame
+    if wind_zones.zones != gpu_zones {
+        wind_zones.zones = gpu_zones;
+    }
+}
+
+//Added by the grass and the instancing plugins, whichever comes first
+pub struct WindPlugin;
+
+impl Plugin for WindPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins(ExtractResourcePlugin::<Wind>::default());
+        app.add_plugins(ExtractResourcePlugin::<WindZones>::default());
+        app.init_resource::<Wind>();
+        app.init_resource::<WindZones>();
+        app.add_systems(
+            PostUpdate,
+            update_wind_zones.after(TransformSystem::TransformPropagate),
+        );
+
+        let render_app = match app.get_sub_app_mut(RenderApp) {
+            Ok(render_app) => render_app,
+            Err(_) => return,
+        };
+
+        render_app.add_systems(Render, prepare_wind.in_set(RenderSet::Prepare));
+    }
+
+    fn finish(&self, app: &mut App) {
+        let render_app = match app.get_sub_app_mut(RenderApp) {
+            Ok(render_app) => render_app,
+            Err(_) => return,
+        };
+
+        render_app.init_resource::<WindBuffer>();
+    }
+}
+
+impl ExtractResource for Wind {
+    type Source = Wind;
+
+    fn extract_resource(res: &Self::Source) -> Self {
+        res.clone()
+    }
+}
+
+impl ExtractResource for WindZones {
+    type Source = WindZones;
+
+    fn extract_resource(res: &Self::Source) -> Self {
+        res.clone()
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Zeroable, Pod)]
+struct GpuWindZone {
+    pub position_radius: [f32; 4],
+    pub direction_strength: [f32; 4], //direction xy (zero for spherical zones), strength, falloff
+}
+
+impl WindZone {
+    fn to_raw(&self, position: Vec3) -> GpuWindZone {
+        let direction = match self.shape {
+            WindZoneShape::Spherical => Vec2::ZERO,
+            WindZoneShape::Directional(direction) => direction.normalize_or_zero(),
+        };
+        GpuWindZone {
+            position_radius: position.extend(self.radius).to_array(),
+            direction_strength: [
+                direction.x,
+                direction.y,
+                self.current_strength(),
+                self.falloff,
+            ],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Zeroable, Pod)]
+struct GpuWind {
+    pub direction_strength: [f32; 4], //direction xy, strength, gustiness
+    pub gust_turbulence: [f32; 4],    //gust scale, gust speed, turbulence
+    pub zone_count: [u32; 4],
+    pub zones: [GpuWindZone; MAX_WIND_ZONES],
+}
+
+impl Wind {
+    fn to_raw(&self, wind_zones: &WindZones) -> GpuWind {
+        let direction = self.direction.normalize_or_zero();
+        let mut gpu_wind = GpuWind {
+            direction_strength: [direction.x, direction.y, self.strength, self.gustiness],
+            gust_turbulence: [self.gust_scale, self.gust_speed, self.turbulence, 0.0],
+            zone_count: [wind_zones.zones.len() as u32, 0, 0, 0],
+            zones: [GpuWindZone::zeroed(); MAX_WIND_ZONES],
+        };
+        gpu_wind.zones[..wind_zones.zones.len()].copy_from_slice(&wind_zones.zones);
+        gpu_wind
+    }
+}
+
+//One uniform buffer bound by every grass and instancing bind group, only rewritten when the wind changes
+#[derive(Resource)]
+pub struct WindBuffer {
+    buffer: Buffer,
+    _shader: Handle<Shader>, //Only held so the shared wind shader module stays loaded, it's imported by the grass and instancing shaders
+}
+
+impl WindBuffer {
+    pub fn as_entire_binding(&self) -> BindingResource<'_> {
+        self.buffer.as_entire_binding()
+    }
+}
+
+impl FromWorld for WindBuffer {
+    fn from_world(world: &mut World) -> Self {
+        let render_device = world.resource::<RenderDevice>();
+        let buffer = render_device.create_buffer_with_data(&BufferInitDescriptor {
+            label: Some("wind_buffer"),
+            contents: bytemuck::bytes_of(&Wind::default().to_raw(&WindZones::default())),
+            usage: BufferUsages::UNIFORM | BufferUsages::COPY_DST,
+        });
+        let shader = world.resource::<AssetServer>().load("shaders/wind.wgsl");
+        WindBuffer {
+            buffer,
+            _shader: shader,
+        }
+    }
+}
+
+fn prepare_wind(
+    render_queue: Res<RenderQueue>,
+    wind: Res<Wind>,
+    wind_zones: Res<WindZones>,
+    wind_buffer: Res<WindBuffer>,
+) {
+    if wind.is_changed() || wind_zones.is_changed() {
+        render_queue.write_buffer(
+            &wind_buffer.buffer,
+            0,
+            bytemuck::bytes_of(&wind.to_raw(&wind_zones)),
+        );
+    }
+}